@@ -0,0 +1,163 @@
+use sdl2::pixels::{Color, PixelFormatEnum};
+use sdl2::rect::Rect;
+use sdl2::render::{Renderer, Texture};
+
+use geom::*;
+
+/// Something that can show a finished frame.
+///
+/// All drawing happens in software on a `Framebuffer`; a backend only has
+/// to report how big its output is and put the frame somewhere.
+pub trait Backend {
+    fn output_size(&self) -> Vec2u;
+    fn set_resolution(&mut self, resolution: Vec2u);
+    fn present(&mut self, frame: &Framebuffer);
+}
+
+/// Tightly packed RGBA pixels, row-major, top row first.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Framebuffer {
+    pub size: Vec2u,
+    pub pixels: Vec<u8>,
+}
+
+/// Backend for rendering without a display. Frames are discarded;
+/// read them back from the `Handler` instead.
+pub struct Headless {
+    size: Vec2u,
+}
+
+pub struct Window<'r> {
+    renderer: Renderer<'r>,
+    screen: Option<Texture>,
+}
+
+impl Framebuffer {
+    pub fn new(size: Vec2u) -> Self {
+        let len = size.x as usize * size.y as usize * 4;
+
+        Framebuffer {
+            size: size,
+            pixels: vec![0; len],
+        }
+    }
+
+    pub fn pitch(&self) -> usize {
+        self.size.x as usize * 4
+    }
+
+    pub fn clear(&mut self, color: Color) {
+        let (r, g, b, a) = color.rgba();
+
+        for px in self.pixels.chunks_mut(4) {
+            px[0] = r;
+            px[1] = g;
+            px[2] = b;
+            px[3] = a;
+        }
+    }
+
+    pub fn get(&self, x: u32, y: u32) -> Color {
+        let i = self.index(x, y);
+        let px = &self.pixels[i .. i + 4];
+        Color::RGBA(px[0], px[1], px[2], px[3])
+    }
+
+    pub fn put(&mut self, x: i32, y: i32, color: Color) {
+        if x < 0 || x >= self.size.x as i32 { return; }
+        if y < 0 || y >= self.size.y as i32 { return; }
+
+        let i = self.index(x as u32, y as u32);
+        let (r, g, b, a) = color.rgba();
+        self.pixels[i] = r;
+        self.pixels[i + 1] = g;
+        self.pixels[i + 2] = b;
+        self.pixels[i + 3] = a;
+    }
+
+    pub fn fill_rect(&mut self, rect: Rect, color: Color) {
+        let (w, h) = (self.size.x as i32, self.size.y as i32);
+
+        let x0 = rect.left().max(0);
+        let x1 = rect.right().min(w);
+        let y0 = rect.top().max(0);
+        let y1 = rect.bottom().min(h);
+
+        for y in y0 .. y1 {
+            for x in x0 .. x1 {
+                self.put(x, y, color);
+            }
+        }
+    }
+
+    #[inline]
+    fn index(&self, x: u32, y: u32) -> usize {
+        (y as usize * self.size.x as usize + x as usize) * 4
+    }
+}
+
+impl Headless {
+    pub fn new(size: Vec2u) -> Self {
+        Headless { size: size }
+    }
+}
+
+impl Backend for Headless {
+    fn output_size(&self) -> Vec2u {
+        self.size
+    }
+
+    fn set_resolution(&mut self, _resolution: Vec2u) {}
+
+    fn present(&mut self, _frame: &Framebuffer) {}
+}
+
+impl<'r> Window<'r> {
+    pub fn new(renderer: Renderer<'r>) -> Self {
+        Window {
+            renderer: renderer,
+            screen: None,
+        }
+    }
+}
+
+impl<'r> Backend for Window<'r> {
+    fn output_size(&self) -> Vec2u {
+        Vec2u::from(self.renderer.window().unwrap().size())
+    }
+
+    fn set_resolution(&mut self, resolution: Vec2u) {
+        self.renderer.set_logical_size(resolution.x, resolution.y)
+            .unwrap();
+
+        // ABGR8888 is RGBA byte order on little-endian machines
+        let format = PixelFormatEnum::ABGR8888;
+        let screen = self.renderer
+            .create_texture_streaming(format, resolution.x, resolution.y)
+            .unwrap();
+
+        self.screen = Some(screen);
+    }
+
+    fn present(&mut self, frame: &Framebuffer) {
+        let screen = match self.screen.as_mut() {
+            Some(screen) => screen,
+            None => return,
+        };
+
+        screen.update(None, &frame.pixels, frame.pitch()).unwrap();
+        self.renderer.copy(screen, None, None).unwrap();
+        self.renderer.present();
+    }
+}
+
+#[test]
+fn fill_rect_clips_to_frame() {
+    let mut frame = Framebuffer::new(Vec2u::new(4, 4));
+    frame.clear(Color::RGB(0, 0, 0));
+    frame.fill_rect(Rect::new(-2, 2, 4, 10), Color::RGB(0xff, 0, 0));
+
+    assert_eq!(frame.get(0, 1).rgba(), (0, 0, 0, 0xff));
+    assert_eq!(frame.get(1, 3).rgba(), (0xff, 0, 0, 0xff));
+    assert_eq!(frame.get(2, 3).rgba(), (0, 0, 0, 0xff));
+}
@@ -0,0 +1,369 @@
+// Compact binary levels, for tools that need to save quickly. Everything
+// is little-endian:
+//
+//     magic        4 bytes, "RHLV"
+//     version      u16
+//     grid size    f32
+//     spawn        f32 x, f32 y, f32 yaw in radians
+//     prop count   u32
+//     props        f32 x, f32 y
+//     chunk count  u32
+//     chunks       i32 origin x, i32 origin y, then CHUNK_SIZE rows of
+//                  CHUNK_SIZE sectors, south first, each as i16 floor,
+//                  i16 ceiling, u8 texture
+//
+// Streamed levels can't be saved, since only the chunks around the player
+// are loaded. `LevelMap::load` reads these as well as text levels.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::Path;
+
+use display::TextureID;
+
+use geom::*;
+
+use level::LevelError;
+
+use map::*;
+
+pub const MAGIC: &'static [u8; 4] = b"RHLV";
+
+// Bump this whenever the layout changes, and teach `read_from` to
+// migrate the old one
+pub const VERSION: u16 = 2;
+
+impl LevelMap {
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut file = BufWriter::new(File::create(path)?);
+        self.write_to(&mut file)?;
+        file.flush()
+    }
+
+    pub fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        if self.source.is_some() {
+            let message = "streamed levels can't be saved";
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, message));
+        }
+
+        self.write_start(w)?;
+
+        // Sorted so that the same level always gives the same bytes
+        let mut chunks = self.chunks().collect::<Vec<_>>();
+        chunks.sort_by_key(|chunk| (chunk.origin.y, chunk.origin.x));
+
+        w.write_all(&(chunks.len() as u32).to_le_bytes())?;
+
+        for chunk in chunks {
+            write_chunk(w, chunk)?;
+        }
+
+        Ok(())
+    }
+
+    // Everything before the chunks
+    fn write_start<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(MAGIC)?;
+        w.write_all(&VERSION.to_le_bytes())?;
+        w.write_all(&self.grid_size.to_le_bytes())?;
+
+        let Pos3D(ref pos, ref yaw) = self.spawn;
+        for &n in [pos.x, pos.y, yaw.0].iter() {
+            w.write_all(&n.to_le_bytes())?;
+        }
+
+        w.write_all(&(self.props.len() as u32).to_le_bytes())?;
+
+        for prop in &self.props {
+            w.write_all(&prop.x.to_le_bytes())?;
+            w.write_all(&prop.y.to_le_bytes())?;
+        }
+
+        Ok(())
+    }
+
+    // A hash of the level as it is now, to tell whether two are the same.
+    // Streamed levels hash every chunk file, one at a time, and come out
+    // the same as they would fully loaded.
+    pub fn fingerprint(&self) -> io::Result<u64> {
+        let mut hash = Fnv(0xcbf29ce484222325);
+
+        let source = match self.source {
+            Some(ref source) => source,
+            None => {
+                self.write_to(&mut hash)?;
+                return Ok(hash.0);
+            },
+        };
+
+        self.write_start(&mut hash)?;
+
+        let coords = source.chunk_coords();
+        hash.write_all(&(coords.len() as u32).to_le_bytes())?;
+
+        for coords in coords {
+            let chunk = source.load_chunk(coords).map_err(|e| match e {
+                LevelError::Io(e) => e,
+                e => io::Error::new(io::ErrorKind::InvalidData, e.to_string()),
+            })?;
+
+            write_chunk(&mut hash, &chunk)?;
+        }
+
+        Ok(hash.0)
+    }
+
+    pub fn read_from<R: Read>(r: &mut R) -> Result<Self, LevelError> {
+        let mut magic = [0; 4];
+        r.read_exact(&mut magic)?;
+
+        if &magic != MAGIC {
+            return Err(LevelError::NotALevel);
+        }
+
+        let level = match read_u16(r)? {
+            1 => read_v1(r),
+            2 => read_v2(r),
+            version => return Err(LevelError::Version(version)),
+        };
+
+        // Running out partway through is the file's fault, not the disk's
+        level.map_err(|e| match e {
+            LevelError::Io(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                LevelError::Corrupt("truncated".to_owned())
+            },
+            e => e,
+        })
+    }
+}
+
+fn write_chunk<W: Write>(w: &mut W, chunk: &Chunk) -> io::Result<()> {
+    w.write_all(&chunk.origin.x.to_le_bytes())?;
+    w.write_all(&chunk.origin.y.to_le_bytes())?;
+
+    for sector in chunk.sectors.iter().flat_map(|row| row.iter()) {
+        w.write_all(&sector.floor_height.to_le_bytes())?;
+        w.write_all(&sector.ceiling_height.to_le_bytes())?;
+        w.write_all(&[sector.texid.0])?;
+    }
+
+    Ok(())
+}
+
+// FNV-1a, which unlike the standard library's hasher won't change, fed
+// through `Write` so levels can be hashed without a copy in memory
+struct Fnv(u64);
+
+impl Write for Fnv {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for &byte in buf {
+            self.0 = (self.0 ^ byte as u64).wrapping_mul(0x100000001b3);
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn read_v1<R: Read>(r: &mut R) -> Result<LevelMap, LevelError> {
+    let mut level = read_start(r)?;
+    read_chunks(r, &mut level)?;
+    Ok(level)
+}
+
+// Version 2 added props between the spawn and the chunks
+fn read_v2<R: Read>(r: &mut R) -> Result<LevelMap, LevelError> {
+    let mut level = read_start(r)?;
+
+    for _ in 0 .. read_u32(r)? {
+        let x = read_f32(r)?;
+        let y = read_f32(r)?;
+        level.props.push(Vec2f::new(x, y));
+    }
+
+    read_chunks(r, &mut level)?;
+    Ok(level)
+}
+
+// The grid size and spawn, which every version starts with
+fn read_start<R: Read>(r: &mut R) -> Result<LevelMap, LevelError> {
+    let grid_size = read_f32(r)?;
+    if !(grid_size > 0.0) {
+        return Err(LevelError::Corrupt(format!("bad grid size {}", grid_size)));
+    }
+
+    let x = read_f32(r)?;
+    let y = read_f32(r)?;
+    let yaw = read_f32(r)?;
+    let spawn = Pos3D(Vec3f::new(x, y, 0.0), Rad(yaw));
+
+    Ok(LevelMap::empty(grid_size, spawn))
+}
+
+fn read_chunks<R: Read>(r: &mut R, level: &mut LevelMap)
+    -> Result<(), LevelError>
+{
+    for _ in 0 .. read_u32(r)? {
+        let origin = Vec2i::new(read_i32(r)?, read_i32(r)?);
+
+        if origin.x % CHUNK_SIZE != 0 || origin.y % CHUNK_SIZE != 0 {
+            let message = format!("bad chunk origin {}, {}", origin.x, origin.y);
+            return Err(LevelError::Corrupt(message));
+        }
+
+        let mut chunk = Chunk::new(origin);
+
+        for sector in chunk.sectors.iter_mut().flat_map(|row| row.iter_mut()) {
+            let floor_height = read_i16(r)?;
+            let ceiling_height = read_i16(r)?;
+            let mut texid = [0];
+            r.read_exact(&mut texid)?;
+
+            *sector = Sector {
+                floor_height: floor_height,
+                ceiling_height: ceiling_height,
+                texid: TextureID(texid[0]),
+            };
+        }
+
+        level.insert_chunk(chunk);
+    }
+
+    Ok(())
+}
+
+fn read_u16<R: Read>(r: &mut R) -> io::Result<u16> {
+    let mut buf = [0; 2];
+    r.read_exact(&mut buf)?;
+    Ok(u16::from_le_bytes(buf))
+}
+
+fn read_i16<R: Read>(r: &mut R) -> io::Result<i16> {
+    let mut buf = [0; 2];
+    r.read_exact(&mut buf)?;
+    Ok(i16::from_le_bytes(buf))
+}
+
+fn read_u32<R: Read>(r: &mut R) -> io::Result<u32> {
+    let mut buf = [0; 4];
+    r.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_i32<R: Read>(r: &mut R) -> io::Result<i32> {
+    let mut buf = [0; 4];
+    r.read_exact(&mut buf)?;
+    Ok(i32::from_le_bytes(buf))
+}
+
+fn read_f32<R: Read>(r: &mut R) -> io::Result<f32> {
+    let mut buf = [0; 4];
+    r.read_exact(&mut buf)?;
+    Ok(f32::from_le_bytes(buf))
+}
+
+#[test]
+fn binary_round_trip() {
+    let level = LevelMap::parse(include_str!("../levels/courtyard.map")).unwrap();
+
+    let mut bytes = Vec::new();
+    level.write_to(&mut bytes).unwrap();
+    assert_eq!(&bytes[.. 4], MAGIC);
+
+    let copy = LevelMap::read_from(&mut &bytes[..]).unwrap();
+    assert_eq!(copy.grid_size, level.grid_size);
+    assert_eq!(copy.spawn.0, level.spawn.0);
+    assert_eq!(copy.spawn.1, level.spawn.1);
+    assert_eq!(copy.props, level.props);
+    assert_eq!(copy.chunks().count(), level.chunks().count());
+
+    for chunk in level.chunks() {
+        let (coords, _) = LevelMap::locate(chunk.origin);
+        let other = copy.chunk(coords).unwrap();
+        assert_eq!(other.origin, chunk.origin);
+        assert!(other.sectors == chunk.sectors);
+    }
+
+    let mut again = Vec::new();
+    copy.write_to(&mut again).unwrap();
+    assert!(again == bytes);
+
+    let fingerprint = |level: &LevelMap| level.fingerprint().unwrap();
+    assert_eq!(fingerprint(&copy), fingerprint(&level));
+    assert!(fingerprint(&LevelMap::new()) != fingerprint(&level));
+}
+
+#[test]
+fn streamed_levels_hash_every_chunk() {
+    use std::path::PathBuf;
+
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("levels/sprawl");
+    let mut streamed = LevelMap::open_dir(&dir).unwrap();
+    let before = streamed.fingerprint().unwrap();
+
+    // Whatever happens to be loaded makes no difference
+    streamed.stream_around(Vec2f::new(4.5, 24.0), 1, 2);
+    assert_eq!(streamed.fingerprint().unwrap(), before);
+
+    // Nor does loading the lot up front
+    let source = streamed.source.take().unwrap();
+    let mut whole = LevelMap::empty(streamed.grid_size, streamed.spawn.clone());
+    whole.props = streamed.props.clone();
+
+    for coords in source.chunk_coords() {
+        whole.insert_chunk(source.load_chunk(coords).unwrap());
+    }
+
+    assert_eq!(whole.fingerprint().unwrap(), before);
+
+    // But streamed levels can't be saved, having only some chunks loaded
+    let streamed = LevelMap::open_dir(&dir).unwrap();
+    assert!(streamed.write_to(&mut Vec::new()).is_err());
+}
+
+#[test]
+fn binary_reads_version_1() {
+    let level = LevelMap::new();
+    let mut bytes = Vec::new();
+    level.write_to(&mut bytes).unwrap();
+
+    // Version 1 is the same, less the props after the spawn
+    let props = 4 + 2 + 4 + 12;
+    let chunks = props + 4 + level.props.len() * 8;
+    let mut old = bytes[.. props].to_vec();
+    old[4] = 1;
+    old.extend_from_slice(&bytes[chunks ..]);
+
+    let copy = LevelMap::read_from(&mut &old[..]).unwrap();
+    assert!(copy.props.is_empty());
+    assert_eq!(copy.chunks().count(), level.chunks().count());
+    assert_eq!(copy.sector(3, 4), level.sector(3, 4));
+}
+
+#[test]
+fn binary_rejects_bad_files() {
+    let mut bytes = Vec::new();
+    LevelMap::new().write_to(&mut bytes).unwrap();
+
+    let read = |bytes: &[u8]| LevelMap::read_from(&mut &bytes[..]);
+
+    match read(b"grid 3.0\n") {
+        Err(LevelError::NotALevel) => (),
+        other => panic!("expected NotALevel, got {:?}", other.map(|_| ())),
+    }
+
+    let mut future = bytes.clone();
+    future[4] = 99;
+    match read(&future) {
+        Err(LevelError::Version(99)) => (),
+        other => panic!("expected Version(99), got {:?}", other.map(|_| ())),
+    }
+
+    match read(&bytes[.. bytes.len() - 1]) {
+        Err(LevelError::Corrupt(ref what)) if what == "truncated" => (),
+        other => panic!("expected truncated, got {:?}", other.map(|_| ())),
+    }
+}
@@ -0,0 +1,367 @@
+// Which keys, mouse buttons, controller buttons and axes do what.
+// Bindings are read from files in the format of assets/bindings.cfg, which
+// also holds the defaults.
+
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use sdl2::controller::{Axis as PadAxis, Button as PadButton};
+use sdl2::keyboard::Scancode;
+use sdl2::mouse::MouseButton;
+
+use editor::Edit;
+
+// Where to look for bindings when none are given on the command line
+pub const BINDINGS_FILE: &'static str = "assets/bindings.cfg";
+
+const DEFAULTS: &'static str = include_str!("../assets/bindings.cfg");
+
+#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
+pub enum Action {
+    Forward,
+    Back,
+    StrafeLeft,
+    StrafeRight,
+    TurnLeft,
+    TurnRight,
+    Run,
+    Jump,
+    Quit,
+    Minimap,
+    Edit(Edit),
+}
+
+// Actions driven by how far something moves rather than whether it's held
+#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
+pub enum Axis {
+    Move,
+    Strafe,
+    Turn,
+    Look,
+}
+
+#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
+pub enum Button {
+    Key(Scancode),
+    Mouse(MouseButton),
+    Pad(PadButton),
+    // A controller trigger pulled at least halfway
+    Trigger(PadAxis),
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum MouseAxis {
+    X,
+    Y,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum AxisInput {
+    // How far the mouse moved this frame
+    Mouse(MouseAxis),
+    // Where a controller stick is held
+    Pad(PadAxis),
+}
+
+#[derive(Clone, Debug)]
+pub struct Bindings {
+    buttons: Vec<(Button, Action)>,
+    // Each input is scaled by its factor, which flips it when negative
+    axes: Vec<(AxisInput, f32, Axis)>,
+    // How far from the middle a stick has to be pushed to count, from 0 to 1
+    dead_zone: f32,
+}
+
+// Raw trigger reading past which it counts as pressed
+pub const TRIGGER_PRESS: i16 = 16384;
+
+static ACTIONS: &'static [(&'static str, Action)] = &[
+    ("forward", Action::Forward),
+    ("back", Action::Back),
+    ("strafe_left", Action::StrafeLeft),
+    ("strafe_right", Action::StrafeRight),
+    ("turn_left", Action::TurnLeft),
+    ("turn_right", Action::TurnRight),
+    ("run", Action::Run),
+    ("jump", Action::Jump),
+    ("quit", Action::Quit),
+    ("minimap", Action::Minimap),
+    ("edit_toggle", Action::Edit(Edit::Toggle)),
+    ("edit_raise", Action::Edit(Edit::Raise)),
+    ("edit_lower", Action::Edit(Edit::Lower)),
+    ("edit_texture", Action::Edit(Edit::NextTexture)),
+    ("edit_save", Action::Edit(Edit::Save)),
+];
+
+static AXES: &'static [(&'static str, Axis)] = &[
+    ("move", Axis::Move),
+    ("strafe", Axis::Strafe),
+    ("turn", Axis::Turn),
+    ("look", Axis::Look),
+];
+
+static AXIS_INPUTS: &'static [(&'static str, AxisInput)] = &[
+    ("mouse_x", AxisInput::Mouse(MouseAxis::X)),
+    ("mouse_y", AxisInput::Mouse(MouseAxis::Y)),
+    ("pad_left_x", AxisInput::Pad(PadAxis::LeftX)),
+    ("pad_left_y", AxisInput::Pad(PadAxis::LeftY)),
+    ("pad_right_x", AxisInput::Pad(PadAxis::RightX)),
+    ("pad_right_y", AxisInput::Pad(PadAxis::RightY)),
+];
+
+static MOUSE_BUTTONS: &'static [(&'static str, MouseButton)] = &[
+    ("mouse_left", MouseButton::Left),
+    ("mouse_middle", MouseButton::Middle),
+    ("mouse_right", MouseButton::Right),
+    ("mouse_x1", MouseButton::X1),
+    ("mouse_x2", MouseButton::X2),
+];
+
+static PAD_BUTTONS: &'static [(&'static str, Button)] = &[
+    ("pad_a", Button::Pad(PadButton::A)),
+    ("pad_b", Button::Pad(PadButton::B)),
+    ("pad_x", Button::Pad(PadButton::X)),
+    ("pad_y", Button::Pad(PadButton::Y)),
+    ("pad_back", Button::Pad(PadButton::Back)),
+    ("pad_guide", Button::Pad(PadButton::Guide)),
+    ("pad_start", Button::Pad(PadButton::Start)),
+    ("pad_left_stick", Button::Pad(PadButton::LeftStick)),
+    ("pad_right_stick", Button::Pad(PadButton::RightStick)),
+    ("pad_left_shoulder", Button::Pad(PadButton::LeftShoulder)),
+    ("pad_right_shoulder", Button::Pad(PadButton::RightShoulder)),
+    ("pad_dpad_up", Button::Pad(PadButton::DPadUp)),
+    ("pad_dpad_down", Button::Pad(PadButton::DPadDown)),
+    ("pad_dpad_left", Button::Pad(PadButton::DPadLeft)),
+    ("pad_dpad_right", Button::Pad(PadButton::DPadRight)),
+    ("pad_left_trigger", Button::Trigger(PadAxis::TriggerLeft)),
+    ("pad_right_trigger", Button::Trigger(PadAxis::TriggerRight)),
+];
+
+impl Default for Bindings {
+    fn default() -> Self {
+        let mut bindings = Bindings {
+            buttons: Vec::new(),
+            axes: Vec::new(),
+            dead_zone: 0.0,
+        };
+        bindings.apply(DEFAULTS).unwrap();
+        bindings
+    }
+}
+
+impl Bindings {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let mut text = String::new();
+        File::open(path).and_then(|mut file| file.read_to_string(&mut text))
+            .map_err(|e| e.to_string())?;
+
+        Bindings::parse(&text)
+    }
+
+    // Starts from the defaults, rebinding whichever actions `text` lists
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut bindings = Bindings::default();
+        bindings.apply(text)?;
+        Ok(bindings)
+    }
+
+    fn apply(&mut self, text: &str) -> Result<(), String> {
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') { continue; }
+
+            let err = |message: String| format!("line {}: {}", i + 1, message);
+
+            let mut parts = line.splitn(2, '=');
+            let name = parts.next().unwrap().trim();
+            let inputs = match parts.next() {
+                Some(inputs) => inputs.split_whitespace(),
+                None => return Err(err("expected 'action = inputs'".into())),
+            };
+
+            let axis = lookup(AXES, name);
+            let action = lookup(ACTIONS, name);
+
+            if name == "dead_zone" {
+                let value = inputs.collect::<Vec<_>>().join(" ");
+                self.dead_zone = value.parse::<f32>().ok()
+                    .filter(|&zone| zone >= 0.0 && zone < 1.0)
+                    .ok_or_else(|| err(format!("bad dead zone: {}", value)))?;
+            } else if let Some(axis) = axis {
+                self.axes.retain(|&(_, _, a)| a != axis);
+
+                for input in inputs {
+                    let (scale, input_name) = match input.starts_with('-') {
+                        true => (-1.0, &input[1 ..]),
+                        false => (1.0, input),
+                    };
+
+                    let axis_input = AXIS_INPUTS.iter()
+                        .find(|&&(n, _)| n == input_name)
+                        .map(|&(_, axis_input)| axis_input)
+                        .ok_or_else(|| err(format!("not an axis: {}", input)))?;
+
+                    // Mouse motion is a distance rather than a position,
+                    // which only makes sense for steering
+                    let steering = axis == Axis::Turn || axis == Axis::Look;
+                    if let AxisInput::Mouse(_) = axis_input {
+                        if !steering {
+                            return Err(err(format!("the mouse can't {}", name)));
+                        }
+                    }
+
+                    self.axes.push((axis_input, scale, axis));
+                }
+            } else if let Some(action) = action {
+                self.buttons.retain(|&(_, a)| a != action);
+
+                for input in inputs {
+                    let button = parse_button(input).ok_or_else(|| {
+                        err(format!("unknown key or button: {}", input))
+                    })?;
+                    self.buttons.push((button, action));
+                }
+            } else {
+                return Err(err(format!("unknown action: {}", name)));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn actions(&self, button: Button) -> Vec<Action> {
+        self.buttons.iter()
+            .filter(|&&(b, _)| b == button)
+            .map(|&(_, action)| action)
+            .collect()
+    }
+
+    // Whether any of the buttons bound to `action` are in `held`
+    pub fn is_held(&self, action: Action, held: &HashSet<Button>) -> bool {
+        self.buttons.iter().any(|&(b, a)| a == action && held.contains(&b))
+    }
+
+    // How far the mouse moving by `rel` moves `axis`
+    pub fn motion(&self, axis: Axis, rel: (i32, i32)) -> f32 {
+        self.axes.iter()
+            .filter(|&&(_, _, a)| a == axis)
+            .map(|&(input, scale, _)| match input {
+                AxisInput::Mouse(MouseAxis::X) => rel.0 as f32 * scale,
+                AxisInput::Mouse(MouseAxis::Y) => rel.1 as f32 * scale,
+                AxisInput::Pad(_) => 0.0,
+            })
+            .sum()
+    }
+
+    // Where the sticks, with raw readings in `pad`, hold `axis`, from -1 to 1
+    pub fn stick(&self, axis: Axis, pad: &HashMap<PadAxis, i16>) -> f32 {
+        let total: f32 = self.axes.iter()
+            .filter(|&&(_, _, a)| a == axis)
+            .map(|&(input, scale, _)| match input {
+                AxisInput::Pad(pad_axis) => {
+                    let value = pad.get(&pad_axis).cloned().unwrap_or(0);
+                    apply_dead_zone(value, self.dead_zone) * scale
+                },
+                AxisInput::Mouse(_) => 0.0,
+            })
+            .sum();
+
+        total.clamp(-1.0, 1.0)
+    }
+}
+
+// Scales a stick reading to -1..1. Anything inside the dead zone comes out
+// as zero, and the rest is stretched so there's no jump at its edge.
+fn apply_dead_zone(value: i16, dead_zone: f32) -> f32 {
+    let value = (value as f32 / 32767.0).max(-1.0);
+    let past = (value.abs() - dead_zone) / (1.0 - dead_zone);
+
+    if past > 0.0 {
+        past.min(1.0) * value.signum()
+    } else {
+        0.0
+    }
+}
+
+fn lookup<T: Copy>(table: &[(&str, T)], name: &str) -> Option<T> {
+    table.iter().find(|&&(n, _)| n == name).map(|&(_, value)| value)
+}
+
+// Keys go by their SDL scancode names, ignoring case
+fn parse_button(name: &str) -> Option<Button> {
+    if let Some(button) = lookup(MOUSE_BUTTONS, name) {
+        return Some(Button::Mouse(button));
+    }
+
+    if let Some(button) = lookup(PAD_BUTTONS, name) {
+        return Some(button);
+    }
+
+    (0 .. 512).filter_map(Scancode::from_i32)
+        .find(|scancode| format!("{:?}", scancode).eq_ignore_ascii_case(name))
+        .map(Button::Key)
+}
+
+#[test]
+fn parse_bindings() {
+    let key = |bindings: &Bindings, code| bindings.actions(Button::Key(code));
+
+    let defaults = Bindings::default();
+    assert_eq!(key(&defaults, Scancode::W), vec![Action::Forward]);
+    assert_eq!(key(&defaults, Scancode::Tab), vec![Action::Edit(Edit::Toggle)]);
+    assert_eq!(defaults.motion(Axis::Look, (3, -2)), -2.0);
+
+    // Moving with the right hand, with the mouse inverted and the right
+    // button running. Key names are positions, and any case will do.
+    let bindings = Bindings::parse("\
+        # IJKL
+        forward = i
+        strafe_left = J
+        quit = Escape
+        run = mouse_right
+        look = -mouse_y
+        minimap =
+    ").unwrap();
+
+    assert_eq!(key(&bindings, Scancode::I), vec![Action::Forward]);
+    assert_eq!(key(&bindings, Scancode::W), vec![]);
+    assert_eq!(key(&bindings, Scancode::J), vec![Action::StrafeLeft]);
+    assert_eq!(key(&bindings, Scancode::M), vec![]);
+    assert_eq!(bindings.actions(Button::Mouse(MouseButton::Right)),
+               vec![Action::Run]);
+    assert_eq!(key(&bindings, Scancode::S), vec![Action::Back]);
+    assert_eq!(bindings.motion(Axis::Look, (3, -2)), 2.0);
+    assert_eq!(bindings.motion(Axis::Turn, (3, -2)), 3.0);
+
+    let error = |text| Bindings::parse(text).unwrap_err();
+    assert_eq!(error("forward = W\nfly = Space"), "line 2: unknown action: fly");
+    assert_eq!(error("\n\nforward = Wat"), "line 3: unknown key or button: Wat");
+    assert_eq!(error("turn = W"), "line 1: not an axis: W");
+    assert_eq!(error("move = -mouse_y"), "line 1: the mouse can't move");
+    assert_eq!(error("dead_zone = 1.5"), "line 1: bad dead zone: 1.5");
+    assert_eq!(error("forward W"), "line 1: expected 'action = inputs'");
+}
+
+#[test]
+fn stick_dead_zone() {
+    let text = "dead_zone = 0.5\nmove = -pad_left_y pad_right_y";
+    let bindings = Bindings::parse(text).unwrap();
+    let stick = |readings: &[(PadAxis, i16)]| {
+        let pad = readings.iter().cloned().collect::<HashMap<_, _>>();
+        bindings.stick(Axis::Move, &pad)
+    };
+
+    assert_eq!(stick(&[]), 0.0);
+    assert_eq!(stick(&[(PadAxis::LeftY, 16000)]), 0.0);
+    assert_eq!(stick(&[(PadAxis::LeftY, -32768)]), 1.0);
+    assert!((stick(&[(PadAxis::LeftY, -24575)]) - 0.5).abs() < 1e-3);
+
+    // Both sticks pushed all the way still only go full speed
+    let both = |left, right| {
+        stick(&[(PadAxis::LeftY, left), (PadAxis::RightY, right)])
+    };
+    assert_eq!(both(32767, -32768), -1.0);
+    assert_eq!(both(32767, 32767), 0.0);
+}
@@ -0,0 +1,46 @@
+// SDL only sends events for game controllers that have been opened, so
+// this keeps every attached one open, following them as they're plugged in
+// and pulled out. SDL announces the ones already attached at startup the
+// same way as ones plugged in later.
+
+use sdl2::{GameControllerSubsystem, Sdl};
+use sdl2::controller::GameController;
+use sdl2::event::Event;
+
+pub struct Controllers {
+    subsystem: GameControllerSubsystem,
+    open: Vec<GameController>,
+}
+
+impl Controllers {
+    pub fn new(sdl: &Sdl) -> Result<Self, String> {
+        Ok(Controllers {
+            subsystem: sdl.game_controller()?,
+            open: Vec::new(),
+        })
+    }
+
+    pub fn handle(&mut self, event: &Event) {
+        match *event {
+            // This `which` is a device index...
+            Event::ControllerDeviceAdded { which, .. } => {
+                match self.subsystem.open(which as u32) {
+                    Ok(controller) => {
+                        println!("Controller connected: {}", controller.name());
+                        self.open.push(controller);
+                    },
+
+                    Err(e) => println!("Couldn't open controller {}: {:?}", which, e),
+                }
+            },
+
+            // ...but this one is an instance ID
+            Event::ControllerDeviceRemoved { which, .. } => {
+                self.open.retain(|controller| controller.instance_id() != which);
+                println!("Controller disconnected");
+            },
+
+            _ => (),
+        }
+    }
+}
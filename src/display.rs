@@ -1,18 +1,21 @@
-use std::collections::HashMap;
-
 use sdl2::pixels::Color;
 use sdl2::rect::Rect;
-use sdl2::render::Renderer;
 
 use specs::{Component, HashMapStorage, Join, RunArg, System, VecStorage};
 use specs::{Planner, World};
 
+use backend::{Backend, Framebuffer};
+
 use engine::Ctx;
 
 use geom::*;
 
 use movement::*;
 
+use texture::{Atlas, Texture};
+
+use tick::{InPhase, PrevPos3D};
+
 use map::*;
 
 pub struct Camera3D {
@@ -20,11 +23,18 @@ pub struct Camera3D {
     pub dim: Vec2u,
     pub yaw: Radf,
     pub pitch: Radf,
+    // Horizontal field of view
+    pub fov: Radf,
+    // How far above the player's Pos3D the camera sits
+    pub eye_height: f32,
 }
 
+// Drawn standing on the entity's Pos3D, facing the camera
 #[derive(Clone, Debug)]
 pub struct Sprite3D {
-    // Empty for now
+    pub texid: TextureID,
+    pub width: f32,
+    pub height: f32,
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -42,9 +52,18 @@ impl Component for Billboard { type Storage = HashMapStorage<Billboard>; }
 #[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
 pub struct TextureID(pub u8);
 
+// Reserved for the parts of a streamed level that haven't loaded yet
+pub const FOG: TextureID = TextureID(255);
+
 struct DisplayList {
     bg: Color,
+    focal_len: f32,
+    horizon_y: i32,
     walls: Vec<WallSlice>,
+    planes: Vec<PlaneSpan>,
+    billboards: Vec<Billboard>,
+    // Where each column's ray stopped, when the minimap wants them
+    rays: Vec<Vec2f>,
 }
 
 struct WallSlice {
@@ -53,106 +72,285 @@ struct WallSlice {
     high_y: i16,
     low_y: i16,
     x: u32,
+    // Texture coordinates: u across the wall, v at the top and bottom
+    u: f32,
+    high_v: f32,
+    low_v: f32,
+}
+
+// The part of a floor or ceiling seen through one screen column
+struct PlaneSpan {
+    texid: TextureID,
+    x: u32,
+    high_y: i16,
+    low_y: i16,
+    // Height of the plane relative to the eye; negative for floors
+    rel_z: f32,
+    // Texture coordinates under the camera and their change per unit depth
+    origin: Vec2f,
+    step: Vec2f,
+}
+
+// Distance from the camera plane of whatever was drawn at each pixel
+struct DepthBuffer {
+    size: Vec2u,
+    depth: Vec<f32>,
+}
+
+// Things drawn over the finished view
+#[derive(Clone, Debug, Default)]
+pub struct Hud {
+    pub crosshair: bool,
+    // Top-down view of the sectors around the camera
+    pub minimap: bool,
+    // Draw this frame's rays on the minimap
+    pub rays: bool,
 }
 
+// How many sectors across the minimap shows
+const MINIMAP_SECTORS: f32 = 15.0;
+
 pub struct MoveCamera;
+pub struct ProjectSprites;
 pub struct Draw;
 
-pub struct Handler<'r> {
-    textures: HashMap<TextureID, Color>,
-    renderer: Renderer<'r>,
-    resolution: Vec2u,
+pub struct Handler<B> {
+    atlas: Atlas,
+    backend: B,
+    frame: Framebuffer,
+    depth: DepthBuffer,
 }
 
-pub fn init<'r>(planner: &mut Planner<Ctx>, mut renderer: Renderer<'r>) -> Handler<'r> {
-    let (width, height) = renderer.window().unwrap().size();
+// How to pick the size of the frame we render into
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Resolution {
+    // Divide the output size by the smallest whole number that brings
+    // the area within this many pixels
+    Budget(u32),
 
-    let desired_res = {
-        let divisor = gcd(width, height);
+    // Always render at this size and let the backend scale it
+    Fixed(Vec2u),
+}
 
-        // TODO: Try multiplying different factors together
-        // and dividing the original resolution by the result
-        // until you get an area within the desired range
-        Vec2u::from(match (width / divisor, height / divisor) {
-            (16, 9) => (400, 225),
-            (8, 5) => (720, 450),
-            (4, 3) => (320, 240),
-            _ => unimplemented!(),
-        })
-    };
+impl Default for Resolution {
+    fn default() -> Self {
+        Resolution::Budget(100_000)
+    }
+}
 
-    renderer.set_logical_size(desired_res.x, desired_res.y)
-        .unwrap();
+impl Resolution {
+    pub fn choose(self, output: Vec2u) -> Vec2u {
+        let budget = match self {
+            Resolution::Fixed(size) => return size,
+            Resolution::Budget(budget) => budget.max(1) as u64,
+        };
 
-    println!("Using resolution: {:?}", desired_res);
+        let (width, height) = (output.x.max(1), output.y.max(1));
+        let area = width as u64 * height as u64;
+
+        let mut divisor = (area as f64 / budget as f64).sqrt().ceil().max(1.0) as u32;
+
+        // Rounding down can leave the area a hair over budget
+        while (width / divisor) as u64 * (height / divisor) as u64 > budget {
+            divisor += 1;
+        }
+
+        Vec2u::new((width / divisor).max(1), (height / divisor).max(1))
+    }
+}
 
-    let mut textures = HashMap::new();
-    textures.insert(TextureID(0), Color::RGB(0x00, 0x00, 0x00));
-    textures.insert(TextureID(1), Color::RGB(0x7f, 0x3f, 0x1f));
-    textures.insert(TextureID(2), Color::RGB(0x00, 0xbf, 0x1f));
-    textures.insert(TextureID(3), Color::RGB(0xbf, 0xbf, 0x1f));
-    textures.insert(TextureID(4), Color::RGB(0xbf, 0xbf, 0xbf));
+pub fn init<B: Backend>(planner: &mut Planner<Ctx>, mut backend: B, mut atlas: Atlas,
+                        resolution: Resolution) -> Handler<B>
+{
+    atlas.insert(FOG, Texture::solid(Color::RGB(0x9f, 0xaf, 0xbf)));
+
+    let desired_res = resolution.choose(backend.output_size());
+
+    backend.set_resolution(desired_res);
+
+    println!("Using resolution: {:?}", desired_res);
 
     let handler = Handler {
-        renderer: renderer,
-        textures: textures,
-        resolution: desired_res,
+        backend: backend,
+        frame: Framebuffer::new(desired_res),
+        depth: DepthBuffer::new(desired_res),
+        atlas: atlas,
     };
 
-    planner.add_system(MoveCamera, "display::MoveCamera", 2);
-    planner.add_system(Draw, "display::Draw", 1);
+    planner.add_system(InPhase::frame(MoveCamera), "display::MoveCamera", 2);
+    planner.add_system(InPhase::frame(ProjectSprites), "display::ProjectSprites", 1);
+    planner.add_system(InPhase::frame(Draw), "display::Draw", 1);
 
     let world = planner.mut_world();
     world.register::<Sprite3D>();
     world.register::<Billboard>();
     world.add_resource(Camera3D::new(desired_res));
     world.add_resource(DisplayList::new(desired_res));
+    world.add_resource(Hud::default());
 
     handler
 }
 
 impl System<Ctx> for MoveCamera {
-    fn run(&mut self, arg: RunArg, _ctx: Ctx) {
-        let (mut camera, pos, player) = arg.fetch(|world| {
+    fn run(&mut self, arg: RunArg, ctx: Ctx) {
+        let (mut camera, pos, prev, pitch, player, entities) = arg.fetch(|world| {
             (world.write_resource::<Camera3D>(),
             world.read::<Pos3D>(),
-            world.read::<IsPlayer>())
+            world.read::<PrevPos3D>(),
+            world.read::<Pitch>(),
+            world.read::<IsPlayer>(),
+            world.entities())
         });
 
-        for (_, &Pos3D(ref pos, ref yaw)) in (&player, &pos).iter() {
-            camera.pos = *pos;
-            camera.yaw = *yaw;
+        for (entity, _, pos) in (&entities, &player, &pos).iter() {
+            let Pos3D(pos, yaw) = interpolate(pos, prev.get(entity), ctx.alpha);
+
+            camera.pos = pos + Vec3f::new(0.0, 0.0, camera.eye_height);
+            camera.yaw = yaw;
+            camera.pitch = match pitch.get(entity) {
+                Some(pitch) => pitch.0,
+                None => Rad(0.0),
+            };
 
             break;
         }
     }
 }
 
+impl System<Ctx> for ProjectSprites {
+    fn run(&mut self, arg: RunArg, ctx: Ctx) {
+        let (mut billboards, camera, pos, prev, sprites, entities) = arg.fetch(|world| {
+            (world.write::<Billboard>(),
+            world.read_resource::<Camera3D>(),
+            world.read::<Pos3D>(),
+            world.read::<PrevPos3D>(),
+            world.read::<Sprite3D>(),
+            world.entities())
+        });
+
+        billboards.clear();
+
+        for (entity, pos, sprite) in (&entities, &pos, &sprites).iter() {
+            let pos = interpolate(pos, prev.get(entity), ctx.alpha);
+
+            if let Some(billboard) = camera.project_sprite(pos.0, sprite) {
+                billboards.insert(entity, billboard);
+            }
+        }
+    }
+}
+
+// Where to draw something that was at `prev` before the latest tick
+fn interpolate(pos: &Pos3D, prev: Option<&PrevPos3D>, alpha: f32) -> Pos3D {
+    match prev {
+        Some(prev) => prev.0.lerp(pos, alpha),
+        None => pos.clone(),
+    }
+}
+
 impl System<Ctx> for Draw {
     fn run(&mut self, arg: RunArg, _ctx: Ctx) {
-        let (mut manifest, camera, level) = arg.fetch(|world| {
+        let (mut manifest, camera, level, hud) = arg.fetch(|world| {
             (world.write_resource::<DisplayList>(),
             world.read_resource::<Camera3D>(),
-            world.read_resource::<LevelMap>())
+            world.read_resource::<LevelMap>(),
+            world.read_resource::<Hud>())
         });
 
-        let player_xy = camera.pos.truncate(); // Vec3f to Vec2f
+        use cgmath::prelude::*;
 
-        let hf = camera.dim.y as f32 / 8.0;
+        let grid_size = level.grid_size;
+        let player_xy = camera.pos.truncate(); // Vec3f to Vec2f
+        let eye_z = camera.pos.z;
+        let forward = camera.forward();
+        let focal_len = camera.focal_len();
+        let origin = player_xy / grid_size;
+
+        manifest.focal_len = focal_len;
+        manifest.horizon_y = camera.horizon_y();
+
+        // How far above the horizon a sector height appears at some depth
+        let project = |height: i16, depth: f32| -> i16 {
+            ((height as f32 * grid_size - eye_z) * focal_len / depth) as i16
+        };
+
+        // Lowest sector height that shows at the top of the screen at some
+        // depth, which is as high as open sky needs to go
+        let screen_top = |depth: f32| -> i16 {
+            let top_z = camera.horizon_y() as f32 * depth / focal_len + eye_z;
+            (top_z / grid_size).ceil() as i16
+        };
 
         for (x, ray) in camera.scatter_rays() {
-            let prev = match level.sector_to_draw(player_xy) {
+            let mut cur = match level.sector_at(player_xy) {
                 Some(sector) => sector,
                 None => continue,
             };
 
-            for hit in ray.cast(level.grid_size) {
+            // Depth where the ray entered the current sector
+            let mut near_z = 0.0;
+            let mut end = player_xy;
+
+            // Rays are scaled so that moving one unit along them moves one
+            // unit away from the camera plane
+            let step = ray.dir / grid_size;
+
+            let mut clip = Clip {
+                high_y: i16::MAX,
+                low_y: i16::MIN,
+            };
+
+            for hit in ray.cast(grid_size) {
                 use geom::dda::RayHit;
 
                 let hit: RayHit = hit;
 
                 if hit.toi > 1000.0 { break; }
 
+                end = hit.poi;
+
+                // Distance to the camera plane, not to the eye, so walls
+                // facing the camera come out straight
+                let z = (hit.poi - player_xy).dot(forward);
+
+                if z > near_z {
+                    let floor_z = cur.floor_height as f32 * grid_size;
+                    let ceiling_z = cur.ceiling_height as f32 * grid_size;
+
+                    if floor_z < eye_z {
+                        let far_y = project(cur.floor_height, z);
+
+                        manifest.planes.extend(clip.plane(PlaneSpan {
+                            texid: cur.texid,
+                            x: x,
+                            high_y: far_y,
+                            low_y: project(cur.floor_height, near_z),
+                            rel_z: floor_z - eye_z,
+                            origin: origin,
+                            step: step,
+                        }));
+
+                        // Anything further away has to show above this edge
+                        clip.low_y = clip.low_y.max(far_y);
+                    }
+
+                    if cur.ceiling_height > 0 && ceiling_z > eye_z {
+                        let far_y = project(cur.ceiling_height, z);
+
+                        manifest.planes.extend(clip.plane(PlaneSpan {
+                            texid: cur.texid,
+                            x: x,
+                            high_y: project(cur.ceiling_height, near_z),
+                            low_y: far_y,
+                            rel_z: ceiling_z - eye_z,
+                            origin: origin,
+                            step: step,
+                        }));
+
+                        clip.high_y = clip.high_y.min(far_y);
+                    }
+                }
+
                 let next: Sector = {
                     let spot = hit.poi + match hit.normal {
                         Cardinal::North => Vec2f::new(0.0, 0.5),
@@ -161,80 +359,405 @@ impl System<Ctx> for Draw {
                         Cardinal::West => Vec2f::new(-0.5, 0.0),
                     };
 
-                    match level.sector_to_draw(spot) {
-                        Some(sector) => sector,
-                        None => break,
+                    // Carry on through unloaded chunks as if they were flat,
+                    // so loaded ones behind them still show up
+                    match level.lookup_at(spot) {
+                        Lookup::Loaded(sector) => sector,
+                        Lookup::Unloaded => Sector { texid: FOG, .. cur },
+                        Lookup::Void => break,
                     }
                 };
 
-                if next.floor_height <= prev.floor_height { continue; }
+                if z > 0.0 {
+                    let u = texture_u(&hit, grid_size);
+
+                    // Face of a step up
+                    if next.floor_height > cur.floor_height {
+                        let top_y = project(next.floor_height, z);
+
+                        manifest.walls.extend(clip.wall(WallSlice {
+                            texid: next.texid,
+                            camera_z: z,
+                            high_y: top_y,
+                            low_y: project(cur.floor_height, z),
+                            x: x,
+                            u: u,
+                            high_v: -next.floor_height as f32,
+                            low_v: -cur.floor_height as f32,
+                        }));
+
+                        clip.low_y = clip.low_y.max(top_y);
+                    }
+
+                    // Underside of a step down in the ceiling. Open sky is
+                    // higher than any ceiling.
+                    let cur_ceiling = match cur.ceiling_height {
+                        0 => screen_top(z),
+                        height => height,
+                    };
 
-                // FIXME: Correct projection
-                let z = hit.toi;
-                let sector_height = next.floor_height as f32 * hf;
+                    let lower_ceiling = next.ceiling_height > 0
+                        && next.ceiling_height < cur_ceiling;
 
-                // Assume current floor height is 0
-                let wall_height = (sector_height / z) as i16;
+                    if lower_ceiling {
+                        let bottom_y = project(next.ceiling_height, z);
 
-                manifest.walls.push(WallSlice {
-                    texid: next.texid,
-                    camera_z: z,
-                    high_y: wall_height,
-                    low_y: -wall_height,
-                    x: x,
-                });
+                        manifest.walls.extend(clip.wall(WallSlice {
+                            texid: next.texid,
+                            camera_z: z,
+                            high_y: project(cur_ceiling, z),
+                            low_y: bottom_y,
+                            x: x,
+                            u: u,
+                            high_v: -cur_ceiling as f32,
+                            low_v: -next.ceiling_height as f32,
+                        }));
 
-                break;
+                        clip.high_y = clip.high_y.min(bottom_y);
+                    }
+                }
+
+                if clip.is_closed() { break; }
+
+                cur = next;
+                near_z = z.max(near_z);
+            }
+
+            if hud.rays {
+                manifest.rays.push(end);
             }
         }
     }
 }
 
-impl<'r> Handler<'r> {
+// Rows of a screen column not yet covered by anything nearer to the
+// camera, as offsets above the horizon
+struct Clip {
+    high_y: i16,
+    low_y: i16,
+}
+
+impl Clip {
+    fn is_closed(&self) -> bool {
+        self.low_y >= self.high_y
+    }
+
+    fn wall(&self, mut wall: WallSlice) -> Option<WallSlice> {
+        let high_y = wall.high_y.min(self.high_y);
+        let low_y = wall.low_y.max(self.low_y);
+
+        if high_y <= low_y { return None; }
+
+        // Keep the texture where it was on the uncut slice
+        let span = wall.high_y as i32 - wall.low_y as i32;
+        let dv = (wall.low_v - wall.high_v) / span as f32;
+        let (top_y, top_v) = (wall.high_y as i32, wall.high_v);
+        let v_at = |y: i16| top_v + dv * (top_y - y as i32) as f32;
+
+        wall.high_v = v_at(high_y);
+        wall.low_v = v_at(low_y);
+        wall.high_y = high_y;
+        wall.low_y = low_y;
+
+        Some(wall)
+    }
+
+    fn plane(&self, mut plane: PlaneSpan) -> Option<PlaneSpan> {
+        plane.high_y = plane.high_y.min(self.high_y);
+        plane.low_y = plane.low_y.max(self.low_y);
+
+        if plane.high_y <= plane.low_y { return None; }
+
+        Some(plane)
+    }
+}
+
+impl<B: Backend> Handler<B> {
     pub fn draw(&mut self, world: &mut World) {
+        use std::cmp::Ordering;
+
         let mut manifest = world.write_resource::<DisplayList>();
 
-        //manifest.billboards.sort_by_key(|b| b.dst_pos.x);
+        {
+            let billboards = world.read::<Billboard>();
+            manifest.billboards.extend((&billboards).iter().cloned());
+        }
+
+        // Furthest first, so nearer sprites cover them
+        manifest.billboards.sort_by(|a, b| {
+            b.depth.partial_cmp(&a.depth).unwrap_or(Ordering::Equal)
+        });
+
+        self.frame.clear(manifest.bg);
+        self.depth.clear();
+
+        let camera_y = manifest.horizon_y;
+        let focal_len = manifest.focal_len;
 
-        self.renderer.set_draw_color(manifest.bg);
-        self.renderer.clear();
+        for plane in manifest.planes.drain(..) {
+            let texture = match self.atlas.get(plane.texid) {
+                Some(t) => t, None => continue,
+            };
 
-        let camera_y = (self.resolution.y / 2) as i32;
+            draw_plane(&mut self.frame, &mut self.depth, texture, &plane,
+                camera_y, focal_len);
+        }
 
         for wall in manifest.walls.drain(..) {
             let x = wall.x as i32;
 
-            let color = match self.textures.get(&wall.texid) {
-                Some(c) => c, None => continue,
+            let texture = match self.atlas.get(wall.texid) {
+                Some(t) => t, None => continue,
             };
 
-            self.renderer.set_draw_color(*color);
             let high_y = camera_y - wall.high_y as i32;
             let low_y = camera_y - wall.low_y as i32;
-            let width = 1;
-            let height = (low_y - high_y).abs() as u32;
 
-            let screen_rect = Rect::new(x, high_y, width, height);
-            self.renderer.draw_rect(screen_rect).unwrap();
+            texture.draw_column(&mut self.frame, x, (high_y, low_y),
+                wall.u, (wall.high_v, wall.low_v));
+            self.depth.fill_column(x, (high_y, low_y), wall.camera_z);
         }
 
-        //for billboard in manifest.billboards.drain(..) {
-        //    self.renderer.set_draw_color(billboard.texid.0);
-        //    let dst_rect = billboard.screen_rect(camera_y);
-        //    self.renderer.fill_rect(dst_rect).unwrap();
-        //}
+        for billboard in manifest.billboards.drain(..) {
+            let texture = match self.atlas.get(billboard.texid) {
+                Some(t) => t, None => continue,
+            };
 
-        self.renderer.present();
+            draw_billboard(&mut self.frame, &self.depth, texture, billboard,
+                camera_y);
+        }
+
+        let hud = world.read_resource::<Hud>();
+
+        if hud.minimap {
+            let camera = world.read_resource::<Camera3D>();
+            let level = world.read_resource::<LevelMap>();
+            draw_minimap(&mut self.frame, &self.atlas, &level, &camera, &manifest.rays);
+        }
+
+        manifest.rays.clear();
+
+        if hud.crosshair {
+            draw_crosshair(&mut self.frame);
+        }
+
+        self.backend.present(&self.frame);
+    }
+
+    pub fn frame(&self) -> &Framebuffer {
+        &self.frame
     }
 }
 
+// Sectors around the camera in the top left corner, north up. Floors are
+// dimmed so that anything standing above eye level stands out.
+fn draw_minimap(frame: &mut Framebuffer, atlas: &Atlas, level: &LevelMap,
+                camera: &Camera3D, rays: &[Vec2f])
+{
+    let size = frame.size.y as i32 / 3;
+    let (left, top) = (4, 4);
+    let center = Vec2i::new(left + size / 2, top + size / 2);
+
+    // World units per pixel
+    let scale = MINIMAP_SECTORS * level.grid_size / size as f32;
+    let eye = camera.pos.truncate();
+
+    let to_screen = |pos: Vec2f| -> Vec2i {
+        let rel = (pos - eye) / scale;
+        Vec2i::new(center.x + rel.x.round() as i32, center.y - rel.y.round() as i32)
+    };
+
+    for y in top .. top + size {
+        for x in left .. left + size {
+            let pos = eye + Vec2f::new((x - center.x) as f32, (center.y - y) as f32) * scale;
+
+            let color = match level.lookup_at(pos) {
+                Lookup::Loaded(sector) => {
+                    let color = match atlas.get(sector.texid) {
+                        Some(texture) => texture.sample(0.5, 0.5),
+                        None => Color::RGB(0xff, 0x00, 0xff),
+                    };
+
+                    if sector.floor_height as f32 * level.grid_size < camera.pos.z {
+                        let (r, g, b) = color.rgb();
+                        Color::RGB(r / 2, g / 2, b / 2)
+                    } else {
+                        color
+                    }
+                },
+
+                Lookup::Unloaded => match atlas.get(FOG) {
+                    Some(texture) => texture.sample(0.5, 0.5),
+                    None => Color::RGB(0x9f, 0xaf, 0xbf),
+                },
+
+                Lookup::Void => Color::RGB(0x00, 0x00, 0x00),
+            };
+
+            frame.put(x, y, color);
+        }
+    }
+
+    let inside = |p: Vec2i| {
+        p.x >= left && p.x < left + size && p.y >= top && p.y < top + size
+    };
+
+    let line = |frame: &mut Framebuffer, to: Vec2i, color: Color| {
+        let (dx, dy) = (to.x - center.x, to.y - center.y);
+        let steps = dx.abs().max(dy.abs()).max(1);
+
+        for i in 0 .. steps + 1 {
+            let p = Vec2i::new(center.x + dx * i / steps, center.y + dy * i / steps);
+            if !inside(p) { break; }
+            frame.put(p.x, p.y, color);
+        }
+    };
+
+    for &end in rays {
+        line(frame, to_screen(end), Color::RGB(0xff, 0xdf, 0x3f));
+    }
+
+    line(frame, to_screen(eye + camera.forward() * scale * 6.0), Color::RGB(0xff, 0xff, 0xff));
+    frame.fill_rect(Rect::new(center.x - 1, center.y - 1, 3, 3), Color::RGB(0xff, 0x3f, 0x3f));
+}
+
+fn draw_crosshair(frame: &mut Framebuffer) {
+    let (x, y) = (frame.size.x as i32 / 2, frame.size.y as i32 / 2);
+    let color = Color::RGB(0xff, 0xff, 0xff);
+
+    frame.fill_rect(Rect::new(x - 3, y, 7, 1), color);
+    frame.fill_rect(Rect::new(x, y - 3, 1, 7), color);
+}
+
+fn draw_plane(frame: &mut Framebuffer, depth_buf: &mut DepthBuffer,
+              texture: &Texture, plane: &PlaneSpan, camera_y: i32, focal_len: f32)
+{
+    let x = plane.x as i32;
+    let high_y = camera_y - plane.high_y as i32;
+    let low_y = camera_y - plane.low_y as i32;
+    let height = frame.size.y as i32;
+
+    for y in high_y.max(0) .. low_y.min(height) {
+        // Rows never straddle the horizon, so this is never zero
+        let dy = camera_y as f32 - (y as f32 + 0.5);
+        let depth = plane.rel_z * focal_len / dy;
+        let uv = plane.origin + plane.step * depth;
+        frame.put(x, y, texture.sample(uv.x, -uv.y));
+        depth_buf.set(x, y, depth);
+    }
+}
+
+// Draws the pixels of a sprite that are nearer than what's already there.
+// Fully transparent texels are skipped.
+fn draw_billboard(frame: &mut Framebuffer, depth_buf: &DepthBuffer,
+                  texture: &Texture, billboard: Billboard, camera_y: i32)
+{
+    let rect = billboard.screen_rect(camera_y);
+    let (width, height) = (frame.size.x as i32, frame.size.y as i32);
+    let (w, h) = (rect.width() as f32, rect.height() as f32);
+
+    for x in rect.left().max(0) .. rect.right().min(width) {
+        let u = ((x - rect.left()) as f32 + 0.5) / w;
+
+        for y in rect.top().max(0) .. rect.bottom().min(height) {
+            if billboard.depth >= depth_buf.get(x, y) { continue; }
+
+            let v = ((y - rect.top()) as f32 + 0.5) / h;
+            let color = texture.sample(u, v);
+
+            if color.rgba().3 == 0 { continue; }
+
+            frame.put(x, y, color);
+        }
+    }
+}
+
+// Where along the wall a ray hit, from 0.0 at the left edge of the face
+// (as seen by the viewer) to 1.0 at the right.
+fn texture_u(hit: &dda::RayHit, grid_size: f32) -> f32 {
+    let along = match hit.normal {
+        Cardinal::East => -hit.poi.y,
+        Cardinal::West => hit.poi.y,
+        Cardinal::North => hit.poi.x,
+        Cardinal::South => -hit.poi.x,
+    } / grid_size;
+
+    along - along.floor()
+}
+
 impl DisplayList {
     fn new(resolution: Vec2u) -> Self {
         DisplayList {
             bg: Color::RGB(0x3f, 0x7f, 0xff),
+            focal_len: 1.0,
+            horizon_y: resolution.y as i32 / 2,
             walls: Vec::with_capacity(resolution.x as usize),
+            planes: Vec::with_capacity(resolution.x as usize * 2),
+            billboards: Vec::new(),
+            rays: Vec::new(),
+        }
+    }
+}
+
+impl DepthBuffer {
+    fn new(size: Vec2u) -> Self {
+        use std::f32;
+
+        DepthBuffer {
+            size: size,
+            depth: vec![f32::INFINITY; size.x as usize * size.y as usize],
+        }
+    }
+
+    fn clear(&mut self) {
+        use std::f32;
+
+        for depth in self.depth.iter_mut() {
+            *depth = f32::INFINITY;
+        }
+    }
+
+    #[inline]
+    fn index(&self, x: i32, y: i32) -> Option<usize> {
+        if x < 0 || x >= self.size.x as i32 { return None; }
+        if y < 0 || y >= self.size.y as i32 { return None; }
+        Some(y as usize * self.size.x as usize + x as usize)
+    }
+
+    fn get(&self, x: i32, y: i32) -> f32 {
+        use std::f32;
+
+        match self.index(x, y) {
+            Some(i) => self.depth[i],
+            None => f32::INFINITY,
         }
     }
+
+    fn set(&mut self, x: i32, y: i32, depth: f32) {
+        if let Some(i) = self.index(x, y) {
+            self.depth[i] = depth;
+        }
+    }
+
+    fn fill_column(&mut self, x: i32, (y0, y1): (i32, i32), depth: f32) {
+        for y in y0.max(0) .. y1.min(self.size.y as i32) {
+            self.set(x, y, depth);
+        }
+    }
+}
+
+impl Hud {
+    // Off, then the map on its own, then the map with rays
+    pub fn cycle_minimap(&mut self) {
+        let (minimap, rays) = match (self.minimap, self.rays) {
+            (false, _) => (true, false),
+            (true, false) => (true, true),
+            (true, true) => (false, false),
+        };
+
+        self.minimap = minimap;
+        self.rays = rays;
+    }
 }
 
 impl Billboard {
@@ -265,21 +788,74 @@ impl Camera3D {
             pos: Vec3f::new(0.0, 0.0, 0.0),
             yaw: Rad(0.0),
             pitch: Rad(0.0),
+            fov: Rad(75f32.to_radians()),
+            eye_height: 1.5,
         }
     }
 
+    pub fn forward(&self) -> Vec2f {
+        let (sin, cos) = self.yaw.0.sin_cos();
+        Vec2f::new(cos, sin)
+    }
+
+    // Distance in pixels from the eye to the projection plane
+    pub fn focal_len(&self) -> f32 {
+        let half_width = self.dim.x as f32 / 2.0;
+        half_width / (self.fov.0 / 2.0).tan()
+    }
+
+    // Screen row of the horizon. Looking up and down shears the view
+    // rather than tilting it, so this is all that pitch changes.
+    pub fn horizon_y(&self) -> i32 {
+        let shear = self.focal_len() * self.pitch.0.tan();
+        self.dim.y as i32 / 2 + shear as i32
+    }
+
+    pub fn right(&self) -> Vec2f {
+        let forward = self.forward();
+        Vec2f::new(forward.y, -forward.x)
+    }
+
+    // Where a sprite standing at `pos` lands on screen, if anywhere
+    pub fn project_sprite(&self, pos: Vec3f, sprite: &Sprite3D) -> Option<Billboard> {
+        use cgmath::prelude::*;
+
+        let rel = (pos - self.pos).truncate();
+        let depth = rel.dot(self.forward());
+
+        if depth < 0.1 { return None; }
+
+        let scale = self.focal_len() / depth;
+        let width = sprite.width * scale;
+        let height = sprite.height * scale;
+        let center_x = self.dim.x as f32 / 2.0 + rel.dot(self.right()) * scale;
+        let left = (center_x - width / 2.0).floor();
+
+        if left >= self.dim.x as f32 || left + width <= 0.0 { return None; }
+
+        let bottom = (pos.z - self.pos.z) * scale;
+
+        Some(Billboard {
+            dst_pos: Vec2i::new(left as i32, bottom as i32),
+            src_pos: Vec2i::new(0, 0),
+            size: Vec2u::new(width.max(1.0) as u32, height.max(1.0) as u32),
+            depth: depth,
+            texid: sprite.texid,
+        })
+    }
+
     fn scatter_rays(&self) -> XRayIter {
         use cgmath::prelude::*;
 
         let rot = Rot2f::from_angle(self.yaw);
-        let plane_len = self.dim.x as f32 / self.dim.y as f32;
+        let plane_len = (self.fov.0 / 2.0).tan();
 
         XRayIter {
             x: 0,
             width: self.dim.x,
             src: self.pos.truncate().cast(),
-            dir: rot.rotate_vector(Vec2f::new(1.0, 0.0)),
-            right: rot.rotate_vector(Vec2f::new(0.0, -plane_len / 2.0)),
+            dir: self.forward(),
+            right: rot.rotate_vector(Vec2f::new(0.0, -plane_len)),
         }
     }
 }
@@ -307,18 +883,53 @@ impl Iterator for XRayIter {
     }
 }
 
-impl LevelMap {
-    #[inline]
-    fn sector_to_draw(&self, poi: Vec2f) -> Option<Sector> {
-        let coords: Vec2i = (poi / self.grid_size).cast();
-        // FIXME: Subtract chunk root
+#[test]
+fn headless_frame() {
+    use backend::Headless;
 
-        if coords.x < 0 || 8 <= coords.x { return None; }
-        if coords.y < 0 || 8 <= coords.y { return None; }
+    let mut planner = Planner::new(World::new(), 1);
 
-        let y = coords.y as usize;
-        let x = coords.x as usize;
+    {
+        let world = planner.mut_world();
+        world.register::<Pos3D>();
+        world.register::<PrevPos3D>();
+        world.register::<Pitch>();
+        world.register::<IsPlayer>();
+        world.add_resource(LevelMap::new());
 
-        Some(self.chunks[0].sectors[y][x])
+        world.create_now()
+            .with(Pos3D::new(13.5, 4.5, 0.0, 90.0))
+            .with(IsPlayer {})
+            .build();
     }
+
+    let size = Vec2u::new(320, 240);
+    let mut handler = init(&mut planner, Headless::new(size), Atlas::placeholders(),
+                           Resolution::default());
+
+    planner.dispatch(Ctx::new());
+    handler.draw(planner.mut_world());
+
+    let frame = handler.frame();
+    assert_eq!(frame.size, Vec2u::new(320, 240));
+    assert_eq!(frame.get(160, 0).rgba(), (0x3f, 0x7f, 0xff, 0xff));
+    assert_eq!(frame.get(160, 120).rgba(), (0x7f, 0x3f, 0x1f, 0xff));
+}
+
+#[test]
+fn budget_resolution() {
+    let choose = |budget, w, h| {
+        let size = Resolution::Budget(budget).choose(Vec2u::new(w, h));
+        (size.x, size.y)
+    };
+
+    assert_eq!(choose(100_000, 320, 240), (320, 240));
+    assert_eq!(choose(100_000, 1920, 1080), (384, 216));
+    assert_eq!(choose(100_000, 3440, 1440), (430, 180));
+    assert_eq!(choose(100_000, 1080, 1920), (216, 384));
+    assert_eq!(choose(100_000, 1366, 768), (341, 192));
+    assert_eq!(choose(100_000, 200, 150), (200, 150));
+
+    let fixed = Resolution::Fixed(Vec2u::new(200, 100));
+    assert_eq!(fixed.choose(Vec2u::new(1920, 1080)), Vec2u::new(200, 100));
 }
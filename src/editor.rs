@@ -0,0 +1,218 @@
+// Editing levels from inside the game. While the editor is on, whatever
+// sector is under the crosshair can be raised, lowered and retextured, and
+// the level saved back over the file it came from.
+
+use std::path::PathBuf;
+
+use specs::World;
+
+use display::{Camera3D, Hud, TextureID, FOG};
+
+use geom::*;
+
+use map::*;
+
+#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
+pub enum Edit {
+    Toggle,
+    Raise,
+    Lower,
+    NextTexture,
+    Save,
+}
+
+pub struct Editor {
+    pub enabled: bool,
+    // Why the editor has to stay off, if it does
+    pub locked: Option<&'static str>,
+    // Binary if it ends in .rhlv, and text otherwise
+    save_path: PathBuf,
+    // What the texture key cycles through
+    textures: Vec<TextureID>,
+}
+
+// How far away the crosshair can pick a sector
+const PICK_RANGE: f32 = 100.0;
+
+impl Editor {
+    pub fn new(save_path: PathBuf, textures: Vec<TextureID>) -> Self {
+        Editor {
+            enabled: false,
+            locked: None,
+            save_path: save_path,
+            textures: textures.into_iter().filter(|&id| id != FOG).collect(),
+        }
+    }
+
+    pub fn update(&mut self, edits: &[Edit], world: &mut World) {
+        for &edit in edits {
+            if let (Edit::Toggle, Some(reason)) = (edit, self.locked) {
+                println!("{}", reason);
+                continue;
+            }
+
+            if edit == Edit::Toggle {
+                self.enabled = !self.enabled;
+                println!("Editor {}", if self.enabled { "on" } else { "off" });
+                continue;
+            }
+
+            if !self.enabled { continue; }
+
+            let camera = world.read_resource::<Camera3D>();
+            let mut level = world.write_resource::<LevelMap>();
+
+            if edit == Edit::Save {
+                let saved = match self.save_path.extension() {
+                    Some(ext) if ext == "rhlv" => level.save(&self.save_path),
+                    _ => level.save_text(&self.save_path),
+                };
+
+                match saved {
+                    Ok(()) => println!("Saved level to {}", self.save_path.display()),
+                    Err(e) => println!("{}: {}", self.save_path.display(), e),
+                }
+
+                continue;
+            }
+
+            let cell = match pick(&level, &camera) {
+                Some(cell) => cell,
+                None => continue,
+            };
+
+            if let Some(sector) = level.sector_mut(cell.x, cell.y) {
+                apply(sector, edit, &self.textures);
+                println!("Sector {}, {}: floor {}, ceiling {}, texture {}",
+                    cell.x, cell.y, sector.floor_height, sector.ceiling_height,
+                    sector.texid.0);
+            }
+        }
+
+        world.write_resource::<Hud>().crosshair = self.enabled;
+    }
+}
+
+// Follows the middle of the view into the level and returns the first
+// sector whose floor, ceiling or side it runs into
+pub fn pick(level: &LevelMap, camera: &Camera3D) -> Option<Vec2i> {
+    let grid = level.grid_size;
+    let src = camera.pos.truncate();
+    let rise = camera.pitch.0.tan();
+
+    let mut cell = level.sector_coords(src);
+    let mut cur = level.sector(cell.x, cell.y)?;
+
+    for hit in Ray2f::new(src, camera.forward()).cast(grid) {
+        if hit.toi > PICK_RANGE { break; }
+
+        // Height of the line of sight where it leaves this sector
+        let z = camera.pos.z + rise * hit.toi;
+
+        let below = |sector: Sector| z < sector.floor_height as f32 * grid;
+        let above = |sector: Sector| {
+            sector.ceiling_height != 0 && z > sector.ceiling_height as f32 * grid
+        };
+
+        if below(cur) || above(cur) {
+            return Some(cell);
+        }
+
+        let next_cell = cell + hit.normal.step();
+        let next = level.sector(next_cell.x, next_cell.y)?;
+
+        if below(next) || above(next) {
+            return Some(next_cell);
+        }
+
+        cell = next_cell;
+        cur = next;
+    }
+
+    None
+}
+
+fn apply(sector: &mut Sector, edit: Edit, textures: &[TextureID]) {
+    match edit {
+        Edit::Raise => {
+            sector.floor_height = sector.floor_height.saturating_add(1);
+
+            // Push a low ceiling up out of the way rather than closing it
+            if sector.ceiling_height != 0 && sector.ceiling_height <= sector.floor_height {
+                sector.ceiling_height = sector.floor_height.saturating_add(1);
+            }
+        },
+
+        Edit::Lower => sector.floor_height = sector.floor_height.saturating_sub(1),
+
+        Edit::NextTexture => {
+            let next = textures.iter().find(|id| id.0 > sector.texid.0)
+                .or_else(|| textures.first());
+
+            if let Some(&id) = next {
+                sector.texid = id;
+            }
+        },
+
+        Edit::Toggle | Edit::Save => (),
+    }
+}
+
+#[test]
+fn pick_under_crosshair() {
+    let level = LevelMap::new();
+    let mut camera = Camera3D::new(Vec2u::new(320, 240));
+    camera.pos = Vec3f::new(13.5, 10.5, 1.5);
+    camera.yaw = Rad(90f32.to_radians());
+
+    // Level with the eyes, the north wall is what's in the way
+    assert_eq!(pick(&level, &camera), Some(Vec2i::new(4, 7)));
+
+    // Looking down, the floor a couple of sectors ahead
+    camera.pitch = Rad(-20f32.to_radians());
+    assert_eq!(pick(&level, &camera), Some(Vec2i::new(4, 4)));
+
+    // Looking up, the sky never ends
+    camera.pitch = Rad(30f32.to_radians());
+    camera.pos.x = 19.5;
+    assert_eq!(pick(&level, &camera), None);
+}
+
+#[test]
+fn edits_change_sector() {
+    let textures = [TextureID(0), TextureID(1), TextureID(4)];
+    let mut sector = Sector { floor_height: 0, ceiling_height: 1, texid: TextureID(1) };
+
+    apply(&mut sector, Edit::Raise, &textures);
+    assert_eq!((sector.floor_height, sector.ceiling_height), (1, 2));
+
+    apply(&mut sector, Edit::Lower, &textures);
+    apply(&mut sector, Edit::Lower, &textures);
+    assert_eq!((sector.floor_height, sector.ceiling_height), (-1, 2));
+
+    // No number of presses overflows the height
+    let mut deep = Sector { floor_height: i16::MIN, .. sector };
+    apply(&mut deep, Edit::Lower, &textures);
+    assert_eq!(deep.floor_height, i16::MIN);
+    let mut high = Sector { floor_height: i16::MAX, ceiling_height: 0, .. sector };
+    apply(&mut high, Edit::Raise, &textures);
+    assert_eq!(high.floor_height, i16::MAX);
+
+    apply(&mut sector, Edit::NextTexture, &textures);
+    assert_eq!(sector.texid, TextureID(4));
+    apply(&mut sector, Edit::NextTexture, &textures);
+    assert_eq!(sector.texid, TextureID(0));
+}
+
+#[test]
+fn locked_editor_stays_off() {
+    let mut world = World::new();
+    world.add_resource(Hud::default());
+
+    let mut editor = Editor::new(PathBuf::from("level.rhlv"), vec![TextureID(1)]);
+    editor.locked = Some("Locked");
+    editor.update(&[Edit::Toggle, Edit::Raise], &mut world);
+
+    assert!(!editor.enabled);
+    assert!(!world.read_resource::<Hud>().crosshair);
+}
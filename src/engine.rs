@@ -1,21 +1,44 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::PathBuf;
+
 use sdl2::{self, Sdl, EventPump};
-use specs::{self, Planner};
+use sdl2::controller::Axis as PadAxis;
+use sdl2::event::{Event, WindowEvent};
+use specs::{self, Join, Planner};
 
-use display;
+use backend;
+use bindings::*;
+use controllers::Controllers;
+use display::{self, Hud, Sprite3D, TextureID};
+use editor::{Edit, Editor};
+use geom::Vec2f;
 
 use input::*;
 use movement::*;
 use map::*;
+use options::Options;
+use replay::{Recorder, Replay};
+use texture::Atlas;
+use tick::*;
 
-pub fn new<'r>() -> Engine<'r> {
+pub fn new<'r>(options: &Options, level: LevelMap, bindings: Bindings) -> Engine<'r> {
     let sdl = sdl2::init().unwrap();
 
-    let window = sdl.video().unwrap()
-        .window("Rayhem", 640, 480)
-        .position_centered()
-        .fullscreen_desktop()
-        .build()
-        .unwrap();
+    let window = {
+        let video = sdl.video().unwrap();
+        let (width, height) = options.window_size.into();
+        let mut builder = video.window("Rayhem", width, height);
+        builder.position_centered();
+
+        // Not resizable, since the frame is sized once at startup
+        if options.fullscreen {
+            builder.fullscreen_desktop();
+        }
+
+        builder.build().unwrap()
+    };
 
     sdl.mouse().set_relative_mouse_mode(true);
 
@@ -24,30 +47,51 @@ pub fn new<'r>() -> Engine<'r> {
         .build()
         .unwrap();
 
-    let mut planner = {
-        let mut world = specs::World::new();
-
-        world.register::<Pos3D>();
-        world.register::<Vel3D>();
-        world.register::<IsPlayer>();
+    let streamed = level.source.is_some();
+    let mut planner = simulation(level);
 
-        world.add_resource(LevelMap::new());
+    let event_pump = sdl.event_pump().unwrap();
+    // Controllers are a nice extra, so not having them isn't fatal
+    let controllers = match Controllers::new(&sdl) {
+        Ok(controllers) => Some(controllers),
+        Err(e) => {
+            println!("Playing without controllers: {}", e);
+            None
+        },
+    };
 
-        world.create_now()
-            .with(Pos3D::new(13.0, 5.0, 6.0, 90.0))
-            .with(Vel3D::new())
-            .with(IsPlayer {})
-            .build();
+    let mut atlas = Atlas::placeholders();
+    match atlas.load_dir("assets/textures") {
+        Ok(count) => println!("Loaded {} textures", count),
+        Err(e) => println!("Using placeholder textures: {}", e),
+    }
 
-        Planner::new(world, 4)
+    // Edited levels are saved over the one that was loaded
+    let save_path = match (options.generate, options.map.as_ref()) {
+        (None, Some(path)) => path.clone(),
+        _ => PathBuf::from("level.rhlv"),
     };
 
-    let event_pump = sdl.event_pump().unwrap();
+    let mut editor = Editor::new(save_path, atlas.ids());
+
+    // Edits to chunks that streamed out would be lost
+    if streamed {
+        editor.locked = Some("Streamed levels can't be edited");
+    }
+
+    let backend = backend::Window::new(renderer);
+    let display_handler = ::display::init(&mut planner, backend, atlas,
+                                          options.resolution);
 
-    let display_handler = ::display::init(&mut planner, renderer);
+    {
+        let world = planner.mut_world();
+        let props = world.read::<IsProp>();
+        let mut sprites = world.write::<Sprite3D>();
 
-    planner.add_system(MovePlayer{}, "Input", 4);
-    planner.add_system(ApplyVelocity{}, "Movement", 3);
+        for (entity, _) in (&world.entities(), &props).iter() {
+            sprites.insert(entity, Sprite3D { texid: TextureID(16), width: 1.5, height: 2.0 });
+        }
+    }
 
     let ctx = Ctx::new();
 
@@ -55,16 +99,109 @@ pub fn new<'r>() -> Engine<'r> {
         sdl: sdl,
         ctx: ctx,
         event_pump: event_pump,
+        controllers: controllers,
         planner: planner,
         display: display_handler,
+        editor: editor,
+        bindings: bindings,
+        ticks: Ticks::new(),
+    }
+}
+
+pub const PLAYER_COLLIDER: Collider = Collider {
+    height: 1.8,
+    radius: 0.4,
+    step_height: 1.0,
+};
+
+// Stands `pos` on the floor, however high it is
+fn on_floor(level: &LevelMap, mut pos: Pos3D) -> Pos3D {
+    if let Some(sector) = level.sector_at(pos.0.truncate()) {
+        pos.0.z = sector.floor_height as f32 * level.grid_size;
     }
+    pos
+}
+
+// The world with a player and the level's props in it and the systems that
+// run on ticks, but nothing to draw it with
+pub fn simulation(level: LevelMap) -> Planner<Ctx> {
+    let mut world = specs::World::new();
+
+    world.register::<Pos3D>();
+    world.register::<PrevPos3D>();
+    world.register::<Vel3D>();
+    world.register::<Locomotion>();
+    world.register::<Pitch>();
+    world.register::<Collider>();
+    world.register::<Body>();
+    world.register::<IsPlayer>();
+    world.register::<IsProp>();
+
+    let spawn = on_floor(&level, level.spawn.clone());
+
+    world.create_now()
+        .with(spawn)
+        .with(Vel3D::new())
+        .with(Locomotion {
+            wish: Vec2f::new(0.0, 0.0),
+            max_speed: 6.0,
+            acceleration: 40.0,
+            friction: 30.0,
+            air_control: 0.3,
+        })
+        .with(Pitch::new())
+        .with(PLAYER_COLLIDER)
+        .with(Body::default())
+        .with(IsPlayer {})
+        .build();
+
+    for prop in &level.props {
+        world.create_now()
+            .with(on_floor(&level, Pos3D::new(prop.x, prop.y, 0.0, 0.0)))
+            .with(Collider { height: 2.0, radius: 0.5, step_height: 0.0 })
+            .with(IsProp {})
+            .build();
+    }
+
+    world.add_resource(level);
+
+    let mut planner = Planner::new(world, 4);
+    let streaming = StreamChunks { load_radius: 2, keep_radius: 3 };
+
+    planner.add_system(InPhase::tick(RememberPositions), "RememberPositions", 6);
+    planner.add_system(InPhase::tick(streaming), "Streaming", 5);
+    planner.add_system(InPhase::tick(MovePlayer{}), "Input", 4);
+    planner.add_system(InPhase::tick(ApplyVelocity{}), "Movement", 3);
+    planner.add_system(InPhase::tick(Separate{}), "Separate", 2);
+
+    planner
+}
+
+// What one controller has held down and where its sticks are
+#[derive(Clone, Debug, Default)]
+struct PadState {
+    held: HashSet<Button>,
+    axes: HashMap<PadAxis, i16>,
 }
 
 #[derive(Clone)]
 pub struct Ctx {
+    pub phase: Phase,
+    // How far between the last two ticks to draw things
+    pub alpha: f32,
+
     pub should_quit: bool,
     pub turn_amount: f32,
-    pub walking: bool,
+    pub look_amount: f32,
+    pub intent: MoveIntent,
+    pub edits: Vec<Edit>,
+    pub cycle_minimap: bool,
+
+    // Keys and mouse buttons down right now, whatever they're bound to
+    held: HashSet<Button>,
+    // Each controller's state, by instance ID, so that one being pulled
+    // out leaves the others alone
+    pads: BTreeMap<i32, PadState>,
 
     pub elapsed: f64,
     pub began: f64,
@@ -72,13 +209,22 @@ pub struct Ctx {
 }
 
 impl Ctx {
-    fn new() -> Self {
+    pub fn new() -> Self {
         use time;
 
         Ctx {
+            phase: Phase::Frame,
+            alpha: 1.0,
+
             should_quit: false,
             turn_amount: 0.0,
-            walking: false,
+            look_amount: 0.0,
+            intent: MoveIntent::default(),
+            edits: Vec::new(),
+            cycle_minimap: false,
+
+            held: HashSet::new(),
+            pads: BTreeMap::new(),
 
             elapsed: 0.0,
             began: time::precise_time_s(),
@@ -86,59 +232,349 @@ impl Ctx {
         }
     }
 
-    fn update(&mut self, event_pump: &mut EventPump) {
-        self.turn_amount = 0.0;
+    // Takes in a frame's worth of events. Mouse motion keeps adding up until
+    // a tick uses it.
+    pub fn update<I>(&mut self, events: I, bindings: &Bindings)
+        where I: IntoIterator<Item=Event>
+    {
+        self.edits.clear();
+        self.cycle_minimap = false;
+
+        for event in events {
+            self.handle(event, bindings);
+        }
+
+        use time;
+
+        let elapsed = time::precise_time_s() - self.began;
+        self.dt = elapsed - self.elapsed;
+        self.elapsed = elapsed;
+    }
 
-        for event in event_pump.poll_iter() {
-            use sdl2::event::Event;
-            use sdl2::keyboard::Keycode;
+    // What to run a simulation tick with
+    pub fn for_tick(&self) -> Ctx {
+        Ctx {
+            phase: Phase::Tick,
+            dt: Timestep::dt(),
+            .. self.clone()
+        }
+    }
 
-            match event {
-                Event::Quit { .. } => { self.should_quit = true; },
+    pub fn handle(&mut self, event: Event, bindings: &Bindings) {
+        match event {
+            Event::Quit { .. } => { self.should_quit = true; },
+
+            Event::KeyDown { scancode: Some(key), .. } => {
+                self.press(None, Button::Key(key), bindings);
+            },
+
+            Event::KeyUp { scancode: Some(key), .. } => {
+                self.release(None, Button::Key(key), bindings);
+            },
+
+            Event::MouseButtonDown { mouse_btn, .. } => {
+                self.press(None, Button::Mouse(mouse_btn), bindings);
+            },
+
+            Event::MouseButtonUp { mouse_btn, .. } => {
+                self.release(None, Button::Mouse(mouse_btn), bindings);
+            },
+
+            Event::MouseMotion { xrel, yrel, .. } => {
+                self.turn_amount += bindings.motion(Axis::Turn, (xrel, yrel));
+                self.look_amount += bindings.motion(Axis::Look, (xrel, yrel));
+            },
+
+            Event::ControllerButtonDown { which, button, .. } => {
+                self.press(Some(which), Button::Pad(button), bindings);
+            },
+
+            Event::ControllerButtonUp { which, button, .. } => {
+                self.release(Some(which), Button::Pad(button), bindings);
+            },
+
+            Event::ControllerAxisMotion { which, axis, value, .. } => {
+                self.pad(which).axes.insert(axis, value);
+
+                if axis == PadAxis::TriggerLeft || axis == PadAxis::TriggerRight {
+                    let trigger = Button::Trigger(axis);
+                    match value >= TRIGGER_PRESS {
+                        true => self.press(Some(which), trigger, bindings),
+                        false => self.release(Some(which), trigger, bindings),
+                    }
+                } else {
+                    self.update_intent(bindings);
+                }
+            },
+
+            // Forget whatever was held on it, so nothing gets stuck
+            Event::ControllerDeviceRemoved { which, .. } => {
+                self.pads.remove(&which);
+                self.update_intent(bindings);
+            },
+
+            // We won't hear about anything let go of while away
+            Event::Window { win_event: WindowEvent::FocusLost, .. } => {
+                self.held.clear();
+                for pad in self.pads.values_mut() {
+                    pad.held.clear();
+                }
+                self.update_intent(bindings);
+            },
+
+            _ => (),
+        }
+    }
 
-                Event::KeyDown { keycode: Some(k), .. } => match k {
-                    Keycode::Q => { self.should_quit = true; },
+    fn pad(&mut self, which: i32) -> &mut PadState {
+        self.pads.entry(which).or_default()
+    }
 
-                    _ => (),
-                },
+    // Buttons held on the controller with instance ID `pad`, or on the
+    // keyboard and mouse
+    fn held_on(&mut self, pad: Option<i32>) -> &mut HashSet<Button> {
+        match pad {
+            Some(which) => &mut self.pad(which).held,
+            None => &mut self.held,
+        }
+    }
 
-                Event::MouseMotion { xrel, yrel, .. } => {
-                    let _ = yrel; // TODO: Implement vlook
-                    self.turn_amount += xrel as f32;
-                },
+    fn press(&mut self, pad: Option<i32>, button: Button, bindings: &Bindings) {
+        // Ignore key repeat
+        if !self.held_on(pad).insert(button) { return; }
 
+        for action in bindings.actions(button) {
+            match action {
+                Action::Quit => { self.should_quit = true; },
+                Action::Minimap => { self.cycle_minimap = true; },
+                Action::Edit(edit) => self.edits.push(edit),
                 _ => (),
             }
         }
 
-        use sdl2::keyboard::Scancode;
-
-        let kb = event_pump.keyboard_state();
+        self.update_intent(bindings);
+    }
 
-        self.walking = kb.is_scancode_pressed(Scancode::W);
+    fn release(&mut self, pad: Option<i32>, button: Button, bindings: &Bindings) {
+        self.held_on(pad).remove(&button);
+        self.update_intent(bindings);
+    }
 
-        use time;
+    fn update_intent(&mut self, bindings: &Bindings) {
+        let mut all_held = self.held.clone();
+        for pad in self.pads.values() {
+            all_held.extend(&pad.held);
+        }
 
-        let elapsed = time::precise_time_s() - self.began;
-        self.dt = elapsed - self.elapsed;
-        self.elapsed = elapsed;
+        let held = |action| bindings.is_held(action, &all_held);
+
+        // Every controller steers, but together no faster than one alone
+        let stick = |axis| {
+            let total: f32 = self.pads.values()
+                .map(|pad| bindings.stick(axis, &pad.axes))
+                .sum();
+            total.clamp(-1.0, 1.0)
+        };
+
+        // Buttons and sticks together, but never faster than either alone
+        let axis = |plus, minus, axis| {
+            let buttons = (held(plus) as i32 - held(minus) as i32) as f32;
+            (buttons + stick(axis)).clamp(-1.0, 1.0)
+        };
+
+        self.intent = MoveIntent {
+            forward: axis(Action::Forward, Action::Back, Axis::Move),
+            strafe: axis(Action::StrafeRight, Action::StrafeLeft, Axis::Strafe),
+            turn: axis(Action::TurnRight, Action::TurnLeft, Axis::Turn),
+            look: stick(Axis::Look),
+            run: held(Action::Run),
+            jump: held(Action::Jump),
+        };
     }
 }
 
+// Recordings only hold what the player did and not what they changed
+const RECORDING: &'static str = "The editor is off while recording or replaying";
+
 pub struct Engine<'r> {
     sdl: Sdl,
     ctx: Ctx,
     event_pump: EventPump,
+    controllers: Option<Controllers>,
     planner: Planner<Ctx>,
-    display: display::Handler<'r>,
+    display: display::Handler<backend::Window<'r>>,
+    editor: Editor,
+    bindings: Bindings,
+    ticks: Ticks<BufWriter<File>, BufReader<File>>,
 }
 
 impl<'r> Engine<'r> {
+    pub fn record(&mut self, recorder: Recorder<BufWriter<File>>) {
+        self.ticks.recorder = Some(recorder);
+        self.editor.locked = Some(RECORDING);
+    }
+
+    pub fn replay(&mut self, replay: Replay<BufReader<File>>) {
+        self.ticks.replay = Some(replay);
+        self.editor.locked = Some(RECORDING);
+    }
+
     pub fn run(&mut self) {
         while !self.ctx.should_quit {
-            self.ctx.update(&mut self.event_pump);
+            let mut controllers = self.controllers.as_mut();
+            let events = self.event_pump.poll_iter().inspect(|event| {
+                if let Some(ref mut controllers) = controllers {
+                    controllers.handle(event);
+                }
+            });
+
+            self.ctx.update(events, &self.bindings);
+            self.ticks.run(&mut self.planner, &mut self.ctx);
+
             self.planner.dispatch(self.ctx.clone());
+            self.editor.update(&self.ctx.edits, self.planner.mut_world());
+
+            if self.ctx.cycle_minimap {
+                self.planner.mut_world().write_resource::<Hud>().cycle_minimap();
+            }
+
             self.display.draw(self.planner.mut_world());
         }
+
+        if let Err(e) = self.ticks.finish(&self.ctx) {
+            println!("Couldn't save recording: {}", e);
+        }
     }
 }
+
+#[test]
+fn events_drive_intent() {
+    use sdl2::keyboard::{Scancode, NOMOD};
+    use sdl2::mouse::{MouseButton, MouseState};
+
+    let key = |down: bool, key: Scancode| match down {
+        true => Event::KeyDown { timestamp: 0, window_id: 0, keycode: None,
+                                 scancode: Some(key), keymod: NOMOD, repeat: false },
+        false => Event::KeyUp { timestamp: 0, window_id: 0, keycode: None,
+                                scancode: Some(key), keymod: NOMOD, repeat: false },
+    };
+
+    let bindings = Bindings::parse("run = mouse_right\nlook = -mouse_y").unwrap();
+    let mut ctx = Ctx::new();
+
+    ctx.handle(key(true, Scancode::W), &bindings);
+    ctx.handle(key(true, Scancode::A), &bindings);
+    ctx.handle(key(true, Scancode::Up), &bindings);
+    ctx.handle(key(false, Scancode::W), &bindings);
+    ctx.handle(Event::MouseButtonDown { timestamp: 0, window_id: 0, which: 0,
+                                        mouse_btn: MouseButton::Right, x: 0, y: 0 }, &bindings);
+
+    // Up still holds forward after W is let go
+    assert_eq!(ctx.intent, MoveIntent { forward: 1.0, strafe: -1.0, run: true,
+                                        .. MoveIntent::default() });
+
+    ctx.handle(Event::MouseMotion { timestamp: 0, window_id: 0, which: 0,
+                                    mousestate: MouseState::from_sdl_state(0),
+                                    x: 0, y: 0, xrel: 4, yrel: 2 }, &bindings);
+    assert_eq!((ctx.turn_amount, ctx.look_amount), (4.0, -2.0));
+
+    ctx.handle(key(true, Scancode::Tab), &bindings);
+    ctx.handle(key(true, Scancode::Tab), &bindings);
+    assert_eq!(ctx.edits, vec![Edit::Toggle]);
+
+    ctx.handle(Event::Window { timestamp: 0, window_id: 0,
+                               win_event: WindowEvent::FocusLost }, &bindings);
+    assert_eq!(ctx.intent, MoveIntent::default());
+
+    ctx.handle(key(true, Scancode::Q), &bindings);
+    assert!(ctx.should_quit);
+}
+
+#[test]
+fn controller_events_drive_intent() {
+    use sdl2::controller::Button as PadButton;
+
+    let axis = |axis, value| {
+        Event::ControllerAxisMotion { timestamp: 0, which: 0, axis: axis, value: value }
+    };
+
+    let bindings = Bindings::parse("edit_toggle = pad_y").unwrap();
+    let mut ctx = Ctx::new();
+
+    ctx.update(vec![
+        axis(PadAxis::LeftY, -32768),
+        axis(PadAxis::LeftX, 3000),
+        axis(PadAxis::RightX, 16384),
+        axis(PadAxis::RightY, 32767),
+        axis(PadAxis::TriggerLeft, 20000),
+        Event::ControllerButtonDown { timestamp: 0, which: 0, button: PadButton::Y },
+    ], &bindings);
+
+    // The left stick's small nudge sideways is inside the dead zone
+    assert_eq!(ctx.intent.forward, 1.0);
+    assert_eq!(ctx.intent.strafe, 0.0);
+    assert!(ctx.intent.turn > 0.3 && ctx.intent.turn < 0.5);
+    assert_eq!(ctx.intent.look, 1.0);
+    assert!(ctx.intent.run);
+    assert_eq!(ctx.edits, vec![Edit::Toggle]);
+
+    // Holding the button doesn't toggle again on the next frame
+    ctx.update(vec![axis(PadAxis::TriggerLeft, 1000)], &bindings);
+    assert!(!ctx.intent.run);
+    assert_eq!(ctx.edits, vec![]);
+
+    // A second controller is kept apart from the first, and outlasts it
+    let second = |axis, value| {
+        Event::ControllerAxisMotion { timestamp: 0, which: 1, axis: axis, value: value }
+    };
+    let events = vec![second(PadAxis::LeftY, 32767),
+                      second(PadAxis::TriggerLeft, 30000)];
+    ctx.update(events, &bindings);
+    assert_eq!(ctx.intent.forward, 0.0);
+    assert!(ctx.intent.run);
+
+    let removed = |which| Event::ControllerDeviceRemoved { timestamp: 0, which: which };
+    ctx.update(vec![removed(0)], &bindings);
+    assert_eq!(ctx.intent.forward, -1.0);
+    assert!(ctx.intent.run);
+
+    ctx.update(vec![removed(1)], &bindings);
+    assert_eq!(ctx.intent, MoveIntent::default());
+}
+
+#[test]
+fn props_stand_where_the_map_says() {
+    use geom::Vec3f;
+
+    let mut level = LevelMap::parse("\
+        prop 4.5 4.5
+        prop 19.5 4.5
+        legend
+        . 0 0 0
+        a 1 0 0
+        map
+        ........
+        ........
+        ........
+        ........
+        ........
+        ........
+        ......a.
+        ........
+    ").unwrap();
+    level.props.push(Vec2f::new(1.5, 22.5));
+
+    let mut planner = simulation(level);
+    let world = planner.mut_world();
+    let (props, pos) = (world.read::<IsProp>(), world.read::<Pos3D>());
+
+    let mut found = (&props, &pos).iter()
+        .map(|(_, pos)| pos.0)
+        .collect::<Vec<_>>();
+    found.sort_by(|a, b| a.x.partial_cmp(&b.x).unwrap());
+
+    // The one on the raised sector stands on top of it
+    assert_eq!(found, vec![Vec3f::new(1.5, 22.5, 0.0),
+                           Vec3f::new(4.5, 4.5, 0.0),
+                           Vec3f::new(19.5, 4.5, 3.0)]);
+}
@@ -0,0 +1,457 @@
+// Procedural levels. The same style, seed and size always give the same
+// map, so a seed is all it takes to share one.
+
+use std::collections::{HashSet, VecDeque};
+use std::str::FromStr;
+
+use rand::{Rng, SeedableRng, XorShiftRng};
+
+use display::TextureID;
+
+use geom::*;
+
+use map::*;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Style {
+    // Rectangular rooms joined by corridors
+    Rooms,
+    // Smoothed random noise, trimmed to its largest open area
+    Caves,
+    // Rolling hills of whole-sector steps
+    Terraces,
+}
+
+// Grid of sectors being generated, row-major from the south-west corner
+struct Grid {
+    size: Vec2i,
+    cells: Vec<Option<Sector>>,
+    // What to fill the cells that weren't opened up with
+    wall: Sector,
+}
+
+// Walls stand two sectors above the floor, out of reach of a jump
+const WALL: Sector = Sector {
+    floor_height: 2,
+    ceiling_height: 0,
+    texid: TextureID(1),
+};
+
+const TERRACE_WALL: Sector = Sector {
+    floor_height: TERRACE_TOP + WALL.floor_height,
+    ceiling_height: 0,
+    texid: TextureID(1),
+};
+
+// Height of the highest terraces
+const TERRACE_TOP: i16 = 4;
+
+// Builds a level `chunks` chunks across in each direction
+pub fn generate(style: Style, seed: u64, chunks: Vec2i) -> LevelMap {
+    let mut rng = XorShiftRng::from_seed([
+        seed as u32,
+        (seed >> 32) as u32,
+        0x9e37_79b9,
+        0x7f4a_7c15,
+    ]);
+
+    let size = chunks * CHUNK_SIZE;
+    let mut grid = Grid::new(size);
+
+    let spawn = match style {
+        Style::Rooms => rooms(&mut grid, &mut rng),
+        Style::Caves => caves(&mut grid, &mut rng),
+        Style::Terraces => terraces(&mut grid, &mut rng),
+    };
+
+    grid.into_level(spawn)
+}
+
+fn rooms<R: Rng>(grid: &mut Grid, rng: &mut R) -> Vec2i {
+    let open = Sector {
+        floor_height: 0,
+        ceiling_height: 0,
+        texid: TextureID(0),
+    };
+
+    let mut centers: Vec<Vec2i> = Vec::new();
+    let mut placed: Vec<(Vec2i, Vec2i)> = Vec::new();
+    let attempts = (grid.size.x * grid.size.y / 8).max(8);
+
+    for _ in 0 .. attempts {
+        let w = rng.gen_range(3, 8);
+        let h = rng.gen_range(3, 8);
+        if w + 2 > grid.size.x || h + 2 > grid.size.y { continue; }
+
+        let min = Vec2i::new(rng.gen_range(1, grid.size.x - w),
+                             rng.gen_range(1, grid.size.y - h));
+        let max = min + Vec2i::new(w, h);
+
+        // Keep a wall between rooms
+        let overlaps = placed.iter().any(|&(a, b)| {
+            min.x <= b.x && a.x <= max.x && min.y <= b.y && a.y <= max.y
+        });
+
+        if overlaps { continue; }
+
+        let floor = Sector {
+            texid: TextureID(rng.gen_range(0, 2) * 3),
+            ceiling_height: if rng.gen_range(0, 4) == 0 { 1 } else { 0 },
+            .. open
+        };
+
+        for y in min.y .. max.y {
+            for x in min.x .. max.x {
+                grid.set(x, y, Some(floor));
+            }
+        }
+
+        let center = Vec2i::new((min.x + max.x) / 2, (min.y + max.y) / 2);
+
+        // Join each room to the one before with an L-shaped corridor
+        if let Some(&prev) = centers.last() {
+            let corner = if rng.gen() {
+                Vec2i::new(center.x, prev.y)
+            } else {
+                Vec2i::new(prev.x, center.y)
+            };
+
+            grid.carve_line(prev, corner, open);
+            grid.carve_line(corner, center, open);
+        }
+
+        placed.push((min, max));
+        centers.push(center);
+    }
+
+    if centers.is_empty() {
+        let center = grid.size / 2;
+        grid.set(center.x, center.y, Some(open));
+        return center;
+    }
+
+    centers[0]
+}
+
+fn caves<R: Rng>(grid: &mut Grid, rng: &mut R) -> Vec2i {
+    let floor = Sector { floor_height: 0, texid: TextureID(4), .. WALL };
+    let wall = Sector { texid: TextureID(2), .. WALL };
+
+    for y in 1 .. grid.size.y - 1 {
+        for x in 1 .. grid.size.x - 1 {
+            if rng.gen_range(0, 100) >= 45 {
+                grid.set(x, y, Some(floor));
+            }
+        }
+    }
+
+    // A cell becomes wall when most of its neighbors are
+    for _ in 0 .. 5 {
+        let mut next = Grid::new(grid.size);
+
+        for y in 1 .. grid.size.y - 1 {
+            for x in 1 .. grid.size.x - 1 {
+                let walls = grid.neighbors(x, y).filter(|&n| !grid.is_open(n)).count();
+                if walls < 5 {
+                    next.set(x, y, Some(floor));
+                }
+            }
+        }
+
+        *grid = next;
+    }
+
+    // Fill in every pocket but the biggest, so it's all one cave
+    let mut biggest = HashSet::new();
+    let mut seen = HashSet::new();
+
+    for y in 0 .. grid.size.y {
+        for x in 0 .. grid.size.x {
+            let cell = Vec2i::new(x, y);
+            if !grid.is_open(cell) || seen.contains(&cell) { continue; }
+
+            let region = grid.flood(cell, 0);
+            seen.extend(region.iter().cloned());
+
+            if region.len() > biggest.len() {
+                biggest = region;
+            }
+        }
+    }
+
+    for y in 0 .. grid.size.y {
+        for x in 0 .. grid.size.x {
+            if !biggest.contains(&Vec2i::new(x, y)) {
+                grid.set(x, y, None);
+            }
+        }
+    }
+
+    grid.wall = wall;
+
+    // The most central open cell
+    let center = grid.size / 2;
+    let distance = |c: &Vec2i| (c.x - center.x).abs() + (c.y - center.y).abs();
+
+    match biggest.iter().min_by_key(|&c| (distance(c), c.y, c.x)) {
+        Some(&spawn) => spawn,
+        None => {
+            grid.set(center.x, center.y, Some(floor));
+            center
+        },
+    }
+}
+
+fn terraces<R: Rng>(grid: &mut Grid, rng: &mut R) -> Vec2i {
+    grid.wall = TERRACE_WALL;
+
+    // A few random hills, each sloping away from its peak
+    let hills = (0 .. rng.gen_range(2, 5)).map(|_| {
+        let peak = Vec2i::new(rng.gen_range(1, grid.size.x - 1),
+                              rng.gen_range(1, grid.size.y - 1));
+        let spread = rng.gen_range(3, 7);
+        (peak, spread)
+    }).collect::<Vec<_>>();
+
+    // Chebyshev falloff keeps neighbors within one step of each other, so
+    // every terrace can be climbed
+    let mut heights = vec![0i16; (grid.size.x * grid.size.y) as usize];
+
+    for y in 1 .. grid.size.y - 1 {
+        for x in 1 .. grid.size.x - 1 {
+            let height = hills.iter().map(|&(peak, spread)| {
+                let d = (x - peak.x).abs().max((y - peak.y).abs());
+                TERRACE_TOP - (d / spread) as i16
+            }).max().unwrap_or(0);
+
+            heights[(y * grid.size.x + x) as usize] = height.max(0);
+        }
+    }
+
+    for y in 1 .. grid.size.y - 1 {
+        for x in 1 .. grid.size.x - 1 {
+            let height = heights[(y * grid.size.x + x) as usize];
+
+            grid.set(x, y, Some(Sector {
+                floor_height: height,
+                ceiling_height: 0,
+                texid: TextureID(height as u8 % 4 + 1),
+            }));
+        }
+    }
+
+    hills[0].0
+}
+
+impl Grid {
+    fn new(size: Vec2i) -> Self {
+        Grid {
+            size: size,
+            cells: vec![None; (size.x * size.y) as usize],
+            wall: WALL,
+        }
+    }
+
+    fn index(&self, x: i32, y: i32) -> Option<usize> {
+        if x < 0 || y < 0 || x >= self.size.x || y >= self.size.y { return None; }
+        Some((y * self.size.x + x) as usize)
+    }
+
+    fn get(&self, cell: Vec2i) -> Option<Sector> {
+        self.index(cell.x, cell.y).and_then(|i| self.cells[i])
+    }
+
+    fn set(&mut self, x: i32, y: i32, sector: Option<Sector>) {
+        // Leave the outermost ring solid
+        if x < 1 || y < 1 || x >= self.size.x - 1 || y >= self.size.y - 1 { return; }
+
+        if let Some(i) = self.index(x, y) {
+            self.cells[i] = sector;
+        }
+    }
+
+    fn is_open(&self, cell: Vec2i) -> bool {
+        self.get(cell).is_some()
+    }
+
+    // The eight cells around one, including off the edge of the grid
+    fn neighbors(&self, x: i32, y: i32) -> impl Iterator<Item=Vec2i> {
+        (-1 .. 2).flat_map(move |dy| (-1 .. 2).map(move |dx| (dx, dy)))
+            .filter(|&(dx, dy)| dx != 0 || dy != 0)
+            .map(move |(dx, dy)| Vec2i::new(x + dx, y + dy))
+    }
+
+    fn carve_line(&mut self, from: Vec2i, to: Vec2i, sector: Sector) {
+        let (mut x, mut y) = (from.x, from.y);
+
+        loop {
+            if !self.is_open(Vec2i::new(x, y)) {
+                self.set(x, y, Some(sector));
+            }
+
+            if x == to.x && y == to.y { break; }
+
+            x += (to.x - x).signum();
+            if x == to.x { y += (to.y - y).signum(); }
+        }
+    }
+
+    // Open cells reachable from `start` without climbing more than `climb`
+    fn flood(&self, start: Vec2i, climb: i16) -> HashSet<Vec2i> {
+        let floor = |cell| self.get(cell).map(|s| s.floor_height);
+        reachable(start, climb, floor)
+    }
+
+    fn into_level(self, spawn: Vec2i) -> LevelMap {
+        let grid_size = 3.0;
+        let x = (spawn.x as f32 + 0.5) * grid_size;
+        let y = (spawn.y as f32 + 0.5) * grid_size;
+        let spawn = Pos3D::new(x, y, 0.0, 90.0);
+
+        let mut level = LevelMap::empty(grid_size, spawn);
+        let chunks = self.size / CHUNK_SIZE;
+
+        for cy in 0 .. chunks.y {
+            for cx in 0 .. chunks.x {
+                let origin = Vec2i::new(cx, cy) * CHUNK_SIZE;
+                let mut chunk = Chunk::new(origin);
+
+                for (ly, row) in chunk.sectors.iter_mut().enumerate() {
+                    for (lx, sector) in row.iter_mut().enumerate() {
+                        let cell = origin + Vec2i::new(lx as i32, ly as i32);
+                        *sector = self.get(cell).unwrap_or(self.wall);
+                    }
+                }
+
+                level.insert_chunk(chunk);
+            }
+        }
+
+        level
+    }
+}
+
+// Sectors reachable by walking from `start` through the four neighbors
+// of each sector, never stepping up more than `climb`. `floor` gives the
+// floor height of a sector, or None if it can't be entered at all.
+pub fn reachable<F>(start: Vec2i, climb: i16, floor: F) -> HashSet<Vec2i>
+    where F: Fn(Vec2i) -> Option<i16>
+{
+    let mut seen = HashSet::new();
+    let mut queue = VecDeque::new();
+
+    if floor(start).is_none() { return seen; }
+
+    seen.insert(start);
+    queue.push_back(start);
+
+    while let Some(cell) = queue.pop_front() {
+        let here = floor(cell).unwrap();
+
+        for &(dx, dy) in [(1, 0), (-1, 0), (0, 1), (0, -1)].iter() {
+            let next = cell + Vec2i::new(dx, dy);
+            if seen.contains(&next) { continue; }
+
+            match floor(next) {
+                Some(there) if there - here <= climb => {
+                    seen.insert(next);
+                    queue.push_back(next);
+                },
+                _ => (),
+            }
+        }
+    }
+
+    seen
+}
+
+impl FromStr for Style {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "rooms" => Ok(Style::Rooms),
+            "caves" => Ok(Style::Caves),
+            "terraces" => Ok(Style::Terraces),
+            _ => Err(format!("unknown level style: {}", s)),
+        }
+    }
+}
+
+#[cfg(test)]
+fn sectors(level: &LevelMap, chunks: Vec2i) -> Vec<Sector> {
+    let size = chunks * CHUNK_SIZE;
+    let mut sectors = Vec::new();
+
+    for y in 0 .. size.y {
+        for x in 0 .. size.x {
+            sectors.push(level.sector(x, y).unwrap());
+        }
+    }
+
+    sectors
+}
+
+#[cfg(test)]
+fn check_connected(style: Style, climb: i16, open: fn(Sector) -> bool) {
+    let chunks = Vec2i::new(3, 2);
+
+    for seed in 0 .. 20 {
+        let level = generate(style, seed, chunks);
+        let all = sectors(&level, chunks);
+        let spawn = level.sector_coords(level.spawn.0.truncate());
+
+        let floor = |cell: Vec2i| {
+            level.sector(cell.x, cell.y)
+                .and_then(|s| if open(s) { Some(s.floor_height) } else { None })
+        };
+
+        let reached = reachable(spawn, climb, floor);
+        let open_count = all.iter().filter(|&&s| open(s)).count();
+
+        assert!(open_count > 20, "{:?} seed {}: only {} open", style, seed, open_count);
+        assert_eq!(reached.len(), open_count, "{:?} seed {} isn't connected", style, seed);
+    }
+}
+
+#[test]
+fn same_seed_same_level() {
+    let chunks = Vec2i::new(2, 2);
+
+    for &style in [Style::Rooms, Style::Caves, Style::Terraces].iter() {
+        let a = sectors(&generate(style, 1234, chunks), chunks);
+        let b = sectors(&generate(style, 1234, chunks), chunks);
+        let c = sectors(&generate(style, 4321, chunks), chunks);
+
+        assert!(a == b, "{:?} isn't reproducible", style);
+        assert!(a != c, "{:?} ignores the seed", style);
+    }
+}
+
+#[test]
+fn rooms_are_connected() {
+    check_connected(Style::Rooms, 0, |s| s.floor_height == 0);
+}
+
+#[test]
+fn caves_are_connected() {
+    check_connected(Style::Caves, 0, |s| s.floor_height == 0);
+}
+
+#[test]
+fn terraces_are_connected() {
+    use engine::PLAYER_COLLIDER;
+    use input::JUMP_SPEED;
+
+    // Each terrace is a whole sector up, more than the player can step but
+    // not more than they can jump
+    let grid_size = generate(Style::Terraces, 0, Vec2i::new(1, 1)).grid_size;
+    let climb = (PLAYER_COLLIDER.reach(JUMP_SPEED) / grid_size).floor() as i16;
+    assert_eq!(climb, 1);
+
+    // Walls are out of reach, even from the highest terrace
+    assert!(climb < WALL.floor_height);
+    assert!(climb < TERRACE_WALL.floor_height - TERRACE_TOP);
+
+    check_connected(Style::Terraces, climb, |s| s != TERRACE_WALL);
+}
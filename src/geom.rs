@@ -1,16 +1,5 @@
 use cgmath;
 
-pub fn gcd(mut a: u32, mut b: u32) -> u32 {
-    use std::mem::swap;
-
-    if b > a { swap(&mut a, &mut b); }
-    loop {
-        if b == 0 || a == b { return a; }
-        a %= b;
-        swap(&mut a, &mut b);
-    }
-}
-
 pub type Vec2u = cgmath::Vector2<u32>;
 pub type Vec2i = cgmath::Vector2<i32>;
 pub type Vec2f = cgmath::Vector2<f32>;
@@ -30,6 +19,18 @@ pub enum Cardinal {
     West,
 }
 
+impl Cardinal {
+    // One grid cell in this direction
+    pub fn step(self) -> Vec2i {
+        match self {
+            Cardinal::North => Vec2i::new(0, 1),
+            Cardinal::East => Vec2i::new(1, 0),
+            Cardinal::South => Vec2i::new(0, -1),
+            Cardinal::West => Vec2i::new(-1, 0),
+        }
+    }
+}
+
 #[derive(Copy, Clone)]
 pub struct Ray2f {
     pub src: Vec2f,
@@ -60,6 +61,18 @@ pub mod dda {
 
     fn horizontal(src: Vec2f, dir: Vec2f, grid_size: f32) -> Iter {
         use cgmath::prelude::*;
+        use std::f32;
+
+        // A ray parallel to the grid lines never crosses one
+        if dir.x == 0.0 {
+            return Iter {
+                poi: src,
+                toi: f32::INFINITY,
+                delta_poi: Vec2f::new(0.0, 0.0),
+                delta_toi: 0.0,
+                normal: Cardinal::East,
+            };
+        }
 
         let slope = dir.y / dir.x;
 
@@ -188,4 +201,21 @@ pub mod dda {
 
         assert_eq!(wanted2, got2);
     }
+
+    #[test]
+    fn raycast_axis_aligned() {
+        let ray = Ray2f {
+            src: Vec2f::new(4.0, 5.0),
+            dir: Vec2f::new(1.0, 0.0),
+        };
+
+        let got = ray.cast(2.0).take(2).map(|hit: RayHit| {
+            (hit.poi, hit.toi)
+        }).collect::<Vec<_>>();
+
+        assert_eq!(got, vec![
+            (Vec2f::new(6.0, 5.0), 2.0),
+            (Vec2f::new(8.0, 5.0), 4.0),
+        ]);
+    }
 }
@@ -0,0 +1,432 @@
+// Golden-image regression tests for the renderer.
+//
+// Each shot puts the camera somewhere in the debug map, renders a frame
+// headlessly and compares it against tests/golden/<name>.png. On failure
+// the actual frame and a diff image are written to target/golden/.
+//
+// Run with RAYHEM_BLESS=1 to (re)write the reference images after an
+// intentional change to the renderer.
+
+use std::env;
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+
+use png;
+
+use specs::{Planner, World};
+
+use backend::{Framebuffer, Headless};
+use display::{self, Camera3D, Hud, Resolution, Sprite3D, TextureID};
+use engine::Ctx;
+use geom::*;
+use map::*;
+use movement::IsPlayer;
+use texture::{Atlas, Texture};
+use tick::PrevPos3D;
+
+const RESOLUTION: (u32, u32) = (320, 240);
+
+struct Shot {
+    name: &'static str,
+    level: fn() -> LevelMap,
+    sprites: &'static [(f32, f32, f32)],
+    pos: (f32, f32, f32),
+    yaw_deg: f32,
+    pitch_deg: f32,
+    // Overlay the minimap, with rays
+    minimap: bool,
+}
+
+struct Tolerance {
+    // Largest allowed difference in any one channel
+    channel: u8,
+    // Number of pixels allowed to exceed the channel tolerance
+    pixels: usize,
+}
+
+// What every shot does unless it says otherwise
+const BASE: Shot = Shot {
+    name: "",
+    level: LevelMap::new,
+    sprites: &[],
+    pos: (0.0, 0.0, 1.5),
+    yaw_deg: 0.0,
+    pitch_deg: 0.0,
+    minimap: false,
+};
+
+static SHOTS: &'static [Shot] = &[
+    Shot {
+        name: "room_north",
+        pos: (13.5, 10.5, 1.5), yaw_deg: 90.0,
+        .. BASE
+    },
+
+    Shot {
+        name: "pillars_west",
+        pos: (19.5, 7.5, 1.5), yaw_deg: 160.0,
+        .. BASE
+    },
+
+    Shot {
+        name: "corner",
+        pos: (4.5, 4.5, 1.5), yaw_deg: 45.0,
+        .. BASE
+    },
+
+    Shot {
+        name: "pillar_close",
+        pos: (16.5, 13.5, 1.5), yaw_deg: 175.0,
+        .. BASE
+    },
+
+    Shot {
+        name: "under_ceiling",
+        pos: (5.5, 18.5, 1.5), yaw_deg: 300.0,
+        .. BASE
+    },
+
+    Shot {
+        name: "into_cover",
+        pos: (19.5, 16.5, 1.5), yaw_deg: 170.0,
+        .. BASE
+    },
+
+    Shot {
+        name: "over_walls",
+        pos: (4.5, 4.5, 7.5), yaw_deg: 40.0,
+        .. BASE
+    },
+
+    Shot {
+        name: "terraces_up", level: terraces,
+        pos: (4.0, 12.5, 7.5), yaw_deg: 5.0,
+        .. BASE
+    },
+
+    Shot {
+        name: "terraces_across", level: terraces,
+        pos: (13.5, 19.5, 8.0), yaw_deg: 265.0,
+        .. BASE
+    },
+
+    Shot {
+        name: "sprites",
+        sprites: &[(14.0, 8.0, 0.0), (12.5, 10.5, 0.0),
+                   (9.0, 5.0, 0.0), (11.0, 9.5, 0.0)],
+        pos: (19.5, 4.5, 1.5), yaw_deg: 150.0,
+        .. BASE
+    },
+
+    Shot {
+        name: "courtyard", level: courtyard,
+        pos: (4.5, 25.5, 1.5), yaw_deg: 350.0, pitch_deg: -10.0,
+        .. BASE
+    },
+
+    Shot {
+        name: "fog", level: sprawl,
+        pos: (4.5, 25.5, 1.5), yaw_deg: 355.0,
+        .. BASE
+    },
+
+    Shot {
+        name: "look_down", level: terraces,
+        pos: (19.5, 12.5, 12.0), yaw_deg: 180.0, pitch_deg: -30.0,
+        .. BASE
+    },
+
+    Shot {
+        name: "look_up",
+        pos: (5.5, 18.5, 1.5), yaw_deg: 300.0, pitch_deg: 30.0,
+        .. BASE
+    },
+
+    Shot {
+        name: "minimap",
+        pos: (19.5, 7.5, 1.5), yaw_deg: 160.0,
+        minimap: true,
+        .. BASE
+    },
+];
+
+static TOLERANCE: Tolerance = Tolerance { channel: 2, pixels: 16 };
+
+pub fn render(level: LevelMap, sprites: &[Vec3f], pos: Vec3f, yaw: Radf,
+              pitch: Radf, minimap: bool) -> Framebuffer
+{
+    let mut planner = Planner::new(World::new(), 1);
+
+    {
+        let world = planner.mut_world();
+        world.register::<Pos3D>();
+        world.register::<PrevPos3D>();
+        world.register::<Pitch>();
+        world.register::<IsPlayer>();
+        world.add_resource(level);
+    }
+
+    let size = Vec2u::from(RESOLUTION);
+    let mut handler = display::init(&mut planner, Headless::new(size), atlas(),
+                                    Resolution::Fixed(size));
+
+    {
+        let world = planner.mut_world();
+
+        for &sprite_pos in sprites {
+            world.create_now()
+                .with(Pos3D(sprite_pos, Rad(0.0)))
+                .with(Sprite3D { texid: TextureID(16), width: 1.5, height: 2.0 })
+                .build();
+        }
+
+        let mut camera = world.write_resource::<Camera3D>();
+        camera.pos = pos;
+        camera.yaw = yaw;
+        camera.pitch = pitch;
+
+        let mut hud = world.write_resource::<Hud>();
+        hud.minimap = minimap;
+        hud.rays = minimap;
+    }
+
+    planner.dispatch(Ctx::new());
+    handler.draw(planner.mut_world());
+    handler.frame().clone()
+}
+
+// Spans four chunks, so rays have to cross chunk boundaries
+fn courtyard() -> LevelMap {
+    LevelMap::parse(include_str!("../levels/courtyard.map")).unwrap()
+}
+
+// A streamed level with a gap of unloaded chunks just east of the camera
+fn sprawl() -> LevelMap {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("levels/sprawl");
+    let mut level = LevelMap::open_dir(dir).unwrap();
+    level.stream_around(Vec2f::new(84.0, 24.0), 1, 10);
+    level.stream_around(Vec2f::new(4.5, 24.0), 0, 10);
+    level
+}
+
+// Rows of steps rising eastward, one grid unit at a time
+fn terraces() -> LevelMap {
+    let mut level = LevelMap::new();
+
+    {
+        let chunk = level.chunk_mut(Vec2i::new(0, 0)).unwrap();
+
+        for (y, row) in chunk.sectors.iter_mut().enumerate() {
+            for (x, sector) in row.iter_mut().enumerate() {
+                let edge = x == 0 || y == 0 || y == 7;
+
+                *sector = Sector {
+                    floor_height: if edge { 4 } else { x as i16 / 2 },
+                    ceiling_height: 0,
+                    texid: TextureID(1 + (x as u8 / 2) % 4),
+                };
+            }
+        }
+    }
+
+    level
+}
+
+// Placeholder colors, plus a couple of patterned textures so that texture
+// mapping shows up in the references without loading any files
+fn atlas() -> Atlas {
+    let mut atlas = Atlas::placeholders();
+    atlas.insert(TextureID(0), checkerboard(2, [0x5f, 0x5f, 0x5f], [0x4f, 0x4f, 0x4f]));
+    atlas.insert(TextureID(1), checkerboard(8, [0x7f, 0x3f, 0x1f], [0x5f, 0x2f, 0x0f]));
+    atlas.insert(TextureID(3), checkerboard(2, [0xbf, 0xbf, 0x1f], [0x3f, 0x3f, 0x0f]));
+    atlas.insert(TextureID(16), diamond(32, [0xff, 0x3f, 0xbf]));
+    atlas
+}
+
+// Opaque diamond on a transparent background
+fn diamond(size: u32, rgb: [u8; 3]) -> Texture {
+    let half = size as i32 / 2;
+    let mut pixels = Vec::with_capacity((size * size * 4) as usize);
+
+    for y in 0 .. size as i32 {
+        for x in 0 .. size as i32 {
+            let inside = (x - half).abs() + (y - half).abs() < half;
+            pixels.extend_from_slice(&rgb);
+            pixels.push(if inside { 0xff } else { 0x00 });
+        }
+    }
+
+    Texture {
+        size: Vec2u::new(size, size),
+        pixels: pixels,
+    }
+}
+
+fn checkerboard(cells: u32, a: [u8; 3], b: [u8; 3]) -> Texture {
+    let size = 16 * cells;
+    let mut pixels = Vec::with_capacity((size * size * 4) as usize);
+
+    for y in 0 .. size {
+        for x in 0 .. size {
+            let rgb = if (x / 16 + y / 16) % 2 == 0 { a } else { b };
+            pixels.extend_from_slice(&rgb);
+            pixels.push(0xff);
+        }
+    }
+
+    Texture {
+        size: Vec2u::new(size, size),
+        pixels: pixels,
+    }
+}
+
+fn reference_path(name: &str) -> PathBuf {
+    let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    path.push("tests");
+    path.push("golden");
+    path.push(format!("{}.png", name));
+    path
+}
+
+fn output_path(name: &str, suffix: &str) -> PathBuf {
+    let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    path.push("target");
+    path.push("golden");
+    fs::create_dir_all(&path).unwrap();
+    path.push(format!("{}.{}.png", name, suffix));
+    path
+}
+
+fn load_png(path: &PathBuf) -> Option<Framebuffer> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(_) => return None,
+    };
+
+    let mut reader = png::Decoder::new(file).read_info().unwrap();
+    let mut pixels = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut pixels).unwrap();
+
+    assert_eq!(info.color_type, png::ColorType::Rgba, "{:?}", path);
+    assert_eq!(info.bit_depth, png::BitDepth::Eight, "{:?}", path);
+
+    pixels.truncate(info.line_size * info.height as usize);
+
+    Some(Framebuffer {
+        size: Vec2u::new(info.width, info.height),
+        pixels: pixels,
+    })
+}
+
+fn save_png(path: &PathBuf, frame: &Framebuffer) {
+    let file = BufWriter::new(File::create(path).unwrap());
+    let mut encoder = png::Encoder::new(file, frame.size.x, frame.size.y);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header().unwrap();
+    writer.write_image_data(&frame.pixels).unwrap();
+}
+
+// Returns the number of pixels that differ by more than the tolerance,
+// along with an image highlighting them in red over a faded reference.
+fn compare(wanted: &Framebuffer, got: &Framebuffer, tol: &Tolerance)
+    -> (usize, Framebuffer)
+{
+    let mut diff = Framebuffer::new(wanted.size);
+    let mut bad = 0;
+
+    let pairs = wanted.pixels.chunks(4).zip(got.pixels.chunks(4));
+    for (i, (a, b)) in pairs.enumerate() {
+        let mismatch = a.iter().zip(b.iter()).any(|(&a, &b)| {
+            (a as i16 - b as i16).abs() > tol.channel as i16
+        });
+
+        let px = &mut diff.pixels[i * 4 .. i * 4 + 4];
+
+        if mismatch {
+            bad += 1;
+            px.copy_from_slice(&[0xff, 0x00, 0x00, 0xff]);
+        } else {
+            let luma = (a[0] as u16 + a[1] as u16 + a[2] as u16) / 3;
+            let faded = (luma / 4) as u8;
+            px.copy_from_slice(&[faded, faded, faded, 0xff]);
+        }
+    }
+
+    (bad, diff)
+}
+
+fn check(shot: &Shot) -> Result<(), String> {
+    let (x, y, z) = shot.pos;
+    let pos = Vec3f::new(x, y, z);
+    let yaw = Rad(shot.yaw_deg.to_radians());
+    let pitch = Rad(shot.pitch_deg.to_radians());
+    let sprites = shot.sprites.iter().map(|&(x, y, z)| {
+        Vec3f::new(x, y, z)
+    }).collect::<Vec<_>>();
+
+    let got = render((shot.level)(), &sprites, pos, yaw, pitch, shot.minimap);
+
+    let path = reference_path(shot.name);
+
+    if env::var_os("RAYHEM_BLESS").is_some() {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        save_png(&path, &got);
+        return Ok(());
+    }
+
+    let wanted = match load_png(&path) {
+        Some(frame) => frame,
+        None => {
+            return Err(format!("{}: missing reference {:?} \
+                (run with RAYHEM_BLESS=1 to create it)", shot.name, path));
+        },
+    };
+
+    if wanted.size != got.size {
+        save_png(&output_path(shot.name, "actual"), &got);
+        return Err(format!("{}: size mismatch (wanted {:?}, got {:?})",
+            shot.name, wanted.size, got.size));
+    }
+
+    let (bad, diff) = compare(&wanted, &got, &TOLERANCE);
+
+    if bad > TOLERANCE.pixels {
+        let actual_path = output_path(shot.name, "actual");
+        let diff_path = output_path(shot.name, "diff");
+        save_png(&actual_path, &got);
+        save_png(&diff_path, &diff);
+
+        return Err(format!("{}: {} pixels differ (see {:?} and {:?})",
+            shot.name, bad, actual_path, diff_path));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn golden_images() {
+    let failures = SHOTS.iter()
+        .filter_map(|shot| check(shot).err())
+        .collect::<Vec<_>>();
+
+    if !failures.is_empty() {
+        panic!("golden image mismatch:\n{}", failures.join("\n"));
+    }
+}
+
+#[test]
+fn compare_respects_tolerance() {
+    let mut a = Framebuffer::new(Vec2u::new(2, 2));
+    let mut b = a.clone();
+    a.pixels[0] = 10;
+    b.pixels[0] = 12;
+    b.pixels[4] = 200;
+
+    let tol = Tolerance { channel: 2, pixels: 0 };
+    let (bad, diff) = compare(&a, &b, &tol);
+
+    assert_eq!(bad, 1);
+    assert_eq!(&diff.pixels[4 .. 8], &[0xff, 0x00, 0x00, 0xff]);
+}
@@ -12,28 +12,115 @@ use movement::*;
 
 pub struct MovePlayer;
 
+// Which way the player wants to go this frame, from the keyboard or a
+// controller. Each axis runs from -1 to 1.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct MoveIntent {
+    // Positive is forward, negative backward
+    pub forward: f32,
+    // Positive is to the right
+    pub strafe: f32,
+    // Positive turns right
+    pub turn: f32,
+    // Positive looks down, the same way as moving the mouse down
+    pub look: f32,
+    pub run: bool,
+    pub jump: bool,
+}
+
+// How far up or down the player can look. Past this the y-shearing
+// used for pitch gets too distorted.
+const MAX_PITCH: f32 = 0.7;
+
+// Units per second
+const WALK_SPEED: f32 = 3.0;
+const RUN_SPEED: f32 = 6.0;
+
+// Upward units per second when jumping, enough to get up a step a whole
+// sector tall
+pub const JUMP_SPEED: f32 = 11.0;
+
+// Radians per second when turning with the keyboard or a stick
+const TURN_SPEED: f32 = 2.5;
+const LOOK_SPEED: f32 = 1.5;
+
+impl MoveIntent {
+    // Direction to move in when facing `yaw`, no longer than one, so that
+    // going diagonally isn't faster than going straight
+    pub fn direction(&self, yaw: Radf) -> Vec2f {
+        use cgmath::prelude::*;
+
+        let (sin, cos) = yaw.sin_cos();
+        let forward = Vec2f::new(cos, sin);
+        let right = Vec2f::new(sin, -cos);
+
+        let dir = forward * self.forward + right * self.strafe;
+
+        if dir.magnitude2() > 1.0 {
+            dir.normalize()
+        } else {
+            dir
+        }
+    }
+
+    pub fn speed(&self) -> f32 {
+        if self.run { RUN_SPEED } else { WALK_SPEED }
+    }
+}
+
 impl System<Ctx> for MovePlayer {
     fn run(&mut self, arg: RunArg, ctx: Ctx) {
-        let (player, mut pos, mut vel) = arg.fetch(|world| {
+        let (player, body, mut pos, mut vel, mut loco, mut pitch, entities) = arg.fetch(|world| {
             (world.read::<IsPlayer>(),
+            world.read::<Body>(),
             world.write::<Pos3D>(),
-            world.write::<Vel3D>())
+            world.write::<Vel3D>(),
+            world.write::<Locomotion>(),
+            world.write::<Pitch>(),
+            world.entities())
         });
 
-        for (_, pos, vel) in (&player, &mut pos, &mut vel).iter() {
-            let turn_speed = Rad(ctx.turn_amount * ctx.dt as f32);
+        let players = (&entities, &player, &mut pos, &mut vel, &mut loco, &mut pitch).iter();
+        for (entity, _, pos, vel, loco, pitch) in players {
+            let intent = ctx.intent;
+            let turn_speed = ctx.turn_amount + intent.turn * TURN_SPEED;
 
-            pos.1 -= turn_speed;
+            pos.1 -= Rad(turn_speed * ctx.dt as f32);
             pos.1 = pos.1.normalize();
 
-            let walk_speed = if ctx.walking {
-                3.0 * ctx.dt as f32
-            } else {
-                0.0
-            };
+            let look_speed = ctx.look_amount + intent.look * LOOK_SPEED;
+            let look = (pitch.0).0 - look_speed * ctx.dt as f32;
+            pitch.0 = Rad(look.clamp(-MAX_PITCH, MAX_PITCH));
+
+            // Getting up to speed is left to the physics
+            loco.wish = intent.direction(pos.1) * intent.speed();
 
-            let (sin, cos) = pos.1.sin_cos();
-            vel.0 = Vec3f::new(walk_speed * cos, walk_speed * sin, 0.0);
+            let on_ground = body.get(entity).is_some_and(|body| body.on_ground);
+            if intent.jump && on_ground {
+                vel.0.z = JUMP_SPEED;
+            }
         }
     }
 }
+
+#[test]
+fn diagonal_is_normalized() {
+    use cgmath::prelude::*;
+
+    let yaw = Rad(0.0);
+    let intent = |forward, strafe| {
+        MoveIntent { forward: forward, strafe: strafe, .. MoveIntent::default() }
+    };
+
+    assert_eq!(intent(1.0, 0.0).direction(yaw), Vec2f::new(1.0, 0.0));
+    assert_eq!(intent(0.0, 1.0).direction(yaw), Vec2f::new(0.0, -1.0));
+    assert_eq!(intent(0.0, 0.0).direction(yaw), Vec2f::new(0.0, 0.0));
+
+    let diagonal = intent(-1.0, -1.0).direction(yaw);
+    assert!((diagonal.magnitude() - 1.0).abs() < 1e-6);
+    assert!(diagonal.x < 0.0 && diagonal.y > 0.0);
+
+    // Half-pressed axes, such as from a stick, stay as they are
+    let gentle = intent(0.5, 0.0).direction(Rad(90f32.to_radians()));
+    assert!((gentle - Vec2f::new(0.0, 0.5)).magnitude() < 1e-6);
+}
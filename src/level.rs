@@ -0,0 +1,671 @@
+// Level files are plain text, one directive per line:
+//
+//     grid 3.0            Size of a sector in world units
+//     spawn 13 5 90       Where the player starts: x, y and facing in degrees
+//     prop 7.5 16.5       Where a piece of scenery stands, as many as needed
+//     legend              Followed by lines of: glyph floor ceiling texture
+//     map                 Followed by the rows of sectors, north first
+//
+// The map runs to the end of the file. Its width and height must both be
+// multiples of the chunk size, and its south-west corner is at the origin.
+// Blank lines and lines starting with '#' are skipped everywhere, so '#'
+// can't be used as a glyph.
+//
+// `LevelMap::write_text` writes levels back out in this format, making up
+// glyphs as it goes, so comments and the original glyphs don't survive.
+
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use binary::{MAGIC, VERSION};
+
+use display::TextureID;
+
+use geom::*;
+
+use map::*;
+
+#[derive(Debug)]
+pub enum LevelError {
+    Io(io::Error),
+    Syntax { line: usize, column: usize, message: String },
+    // Binary files only
+    NotALevel,
+    Version(u16),
+    Corrupt(String),
+}
+
+enum Section {
+    Header,
+    Legend,
+    Map,
+}
+
+struct Row<'a> {
+    line: usize,
+    column: usize,
+    glyphs: &'a str,
+}
+
+// Handed out to sectors in order when writing a level as text
+static GLYPHS: &'static str = "abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ\
+                               0123456789.:!$%&*+-/;<=>?@^_~|";
+
+impl LevelMap {
+    // Reads either a text or a binary level
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, LevelError> {
+        let mut bytes = Vec::new();
+        File::open(path)?.read_to_end(&mut bytes)?;
+
+        if bytes.starts_with(MAGIC) {
+            return LevelMap::read_from(&mut &bytes[..]);
+        }
+
+        match String::from_utf8(bytes) {
+            Ok(text) => LevelMap::parse(&text),
+            Err(_) => Err(LevelError::NotALevel),
+        }
+    }
+
+    pub fn parse(text: &str) -> Result<Self, LevelError> {
+        let header = parse_header(text)?;
+        let rows = header.rows;
+
+        if header.map_line == 0 {
+            let end = text.lines().count();
+            return Err(syntax(end.max(1), 1, "missing map".into()));
+        }
+
+        let size = CHUNK_SIZE as usize;
+        let height = rows.len();
+        let width = rows.first().map_or(0, |row| row.glyphs.chars().count());
+
+        if height == 0 || height % size != 0 {
+            let line = rows.last().map_or(header.map_line, |row| row.line);
+            let message = format!("map has {} rows, which is not a multiple of {}",
+                                  height, size);
+            return Err(syntax(line, 1, message));
+        }
+
+        if width % size != 0 {
+            let row = &rows[0];
+            let message = format!("map has {} columns, which is not a multiple of {}",
+                                  width, size);
+            return Err(syntax(row.line, row.column, message));
+        }
+
+        // Default to the middle of the map, facing east
+        let grid_size = header.grid_size;
+        let spawn = header.spawn.unwrap_or_else(|| {
+            let x = width as f32 * grid_size / 2.0;
+            let y = height as f32 * grid_size / 2.0;
+            Pos3D::new(x, y, 0.0, 0.0)
+        });
+
+        let mut level = LevelMap::empty(grid_size, spawn);
+        level.props = header.props;
+
+        for cy in 0 .. height / size {
+            for cx in 0 .. width / size {
+                let origin = Vec2i::new(cx as i32, cy as i32) * CHUNK_SIZE;
+                level.insert_chunk(Chunk::new(origin));
+            }
+        }
+
+        for (i, row) in rows.iter().enumerate() {
+            let sectors = row.sectors(&header.legend, width)?;
+            let y = (height - 1 - i) as i32;
+
+            for (x, sector) in sectors.into_iter().enumerate() {
+                let (chunk, local) = LevelMap::locate(Vec2i::new(x as i32, y));
+                let chunk = level.chunk_mut(chunk).unwrap();
+                chunk.sectors[local.y as usize][local.x as usize] = sector;
+            }
+        }
+
+        Ok(level)
+    }
+
+    pub fn save_text<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut file = BufWriter::new(File::create(path)?);
+        self.write_text(&mut file)?;
+        file.flush()
+    }
+
+    // Only levels whose chunks fill a rectangle from the origin fit the
+    // format, and only as many different sectors as there are glyphs
+    pub fn write_text<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let invalid = |message: &str| {
+            io::Error::new(io::ErrorKind::InvalidInput, message)
+        };
+
+        if self.source.is_some() {
+            return Err(invalid("streamed levels can't be saved"));
+        }
+
+        let size = self.chunks().fold(Vec2i::new(0, 0), |size, chunk| {
+            Vec2i::new(size.x.max(chunk.origin.x + CHUNK_SIZE),
+                       size.y.max(chunk.origin.y + CHUNK_SIZE))
+        });
+
+        let count = (size.x / CHUNK_SIZE) * (size.y / CHUNK_SIZE);
+        let from_origin = self.chunks()
+            .all(|chunk| chunk.origin.x >= 0 && chunk.origin.y >= 0);
+
+        if count == 0 || count != self.chunks().count() as i32 || !from_origin {
+            return Err(invalid("only a rectangle of chunks from the origin \
+                fits in a text level"));
+        }
+
+        let mut legend: Vec<Sector> = Vec::new();
+        let mut rows = Vec::new();
+
+        for y in (0 .. size.y).rev() {
+            let mut row = String::new();
+
+            for x in 0 .. size.x {
+                let sector = self.sector(x, y).unwrap();
+                let i = match legend.iter().position(|&s| s == sector) {
+                    Some(i) => i,
+                    None => {
+                        legend.push(sector);
+                        legend.len() - 1
+                    },
+                };
+
+                match GLYPHS.chars().nth(i) {
+                    Some(glyph) => row.push(glyph),
+                    None => return Err(invalid("too many different sectors")),
+                }
+            }
+
+            rows.push(row);
+        }
+
+        let Pos3D(ref pos, ref yaw) = self.spawn;
+        writeln!(w, "grid {}", self.grid_size)?;
+        writeln!(w, "spawn {} {} {}", pos.x, pos.y, yaw.0.to_degrees())?;
+
+        for prop in &self.props {
+            writeln!(w, "prop {} {}", prop.x, prop.y)?;
+        }
+
+        writeln!(w, "\nlegend")?;
+
+        for (glyph, sector) in GLYPHS.chars().zip(&legend) {
+            writeln!(w, "{} {} {} {}", glyph, sector.floor_height,
+                     sector.ceiling_height, sector.texid.0)?;
+        }
+
+        writeln!(w, "\nmap")?;
+
+        for row in rows {
+            writeln!(w, "{}", row)?;
+        }
+
+        Ok(())
+    }
+
+    // Opens a level that streams its chunks in from a directory as they
+    // are needed. No chunks are loaded until the first `stream_around`.
+    pub fn open_dir<P: AsRef<Path>>(dir: P) -> Result<Self, LevelError> {
+        let dir = dir.as_ref();
+        let text = read(dir.join("world.map"))?;
+        let header = parse_header(&text)?;
+
+        if header.map_line != 0 {
+            let message = "a streamed level keeps its map in chunk files";
+            return Err(syntax(header.map_line, 1, message.into()));
+        }
+
+        let mut available = HashSet::new();
+
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+
+            if path.extension().and_then(|e| e.to_str()) != Some("chunk") {
+                continue;
+            }
+
+            let coords = path.file_stem().and_then(|s| s.to_str()).and_then(|stem| {
+                let mut parts = stem.splitn(2, '_').map(|n| n.parse::<i32>().ok());
+                match (parts.next(), parts.next()) {
+                    (Some(Some(x)), Some(Some(y))) => Some(Vec2i::new(x, y)),
+                    _ => None,
+                }
+            });
+
+            if let Some(coords) = coords {
+                available.insert(coords);
+            }
+        }
+
+        // Default to the middle of chunk 0, 0, facing east
+        let grid_size = header.grid_size;
+        let spawn = header.spawn.unwrap_or_else(|| {
+            let middle = CHUNK_SIZE as f32 * grid_size / 2.0;
+            Pos3D::new(middle, middle, 0.0, 0.0)
+        });
+
+        let mut level = LevelMap::empty(grid_size, spawn);
+        level.props = header.props;
+
+        level.source = Some(ChunkSource {
+            dir: dir.to_owned(),
+            legend: header.legend,
+            available: available,
+        });
+
+        Ok(level)
+    }
+}
+
+// Where a streamed level gets its chunks from. The directory holds a
+// world.map with everything but the map, and a file of CHUNK_SIZE rows
+// named <x>_<y>.chunk for each chunk, by chunk coordinates.
+#[derive(Clone, Debug)]
+pub struct ChunkSource {
+    dir: PathBuf,
+    legend: HashMap<char, Sector>,
+    available: HashSet<Vec2i>,
+}
+
+impl ChunkSource {
+    pub fn has_chunk(&self, coords: Vec2i) -> bool {
+        self.available.contains(&coords)
+    }
+
+    // Every chunk there is, loaded or not, south-west first
+    pub fn chunk_coords(&self) -> Vec<Vec2i> {
+        let mut coords = self.available.iter().cloned().collect::<Vec<_>>();
+        coords.sort_by_key(|c| (c.y, c.x));
+        coords
+    }
+
+    pub fn chunk_path(&self, coords: Vec2i) -> PathBuf {
+        self.dir.join(format!("{}_{}.chunk", coords.x, coords.y))
+    }
+
+    pub fn load_chunk(&self, coords: Vec2i) -> Result<Chunk, LevelError> {
+        let text = read(self.chunk_path(coords))?;
+        let size = CHUNK_SIZE as usize;
+        let mut chunk = Chunk::new(coords * CHUNK_SIZE);
+        let mut rows = 0;
+
+        for (i, line) in text.lines().enumerate() {
+            let row = match Row::new(i + 1, line) {
+                Some(row) => row,
+                None => continue,
+            };
+
+            if rows == size {
+                let message = format!("expected {} rows", size);
+                return Err(syntax(row.line, 1, message));
+            }
+
+            let sectors = row.sectors(&self.legend, size)?;
+            chunk.sectors[size - 1 - rows].copy_from_slice(&sectors);
+            rows += 1;
+        }
+
+        if rows != size {
+            let end = text.lines().count();
+            let message = format!("expected {} rows, found {}", size, rows);
+            return Err(syntax(end.max(1), 1, message));
+        }
+
+        Ok(chunk)
+    }
+
+    // Stops offering a chunk, say because its file turned out to be bad
+    pub fn forget(&mut self, coords: Vec2i) {
+        self.available.remove(&coords);
+    }
+}
+
+// Everything before the map, plus the rows of the map if there is one
+struct Header<'a> {
+    grid_size: f32,
+    spawn: Option<Pos3D>,
+    props: Vec<Vec2f>,
+    legend: HashMap<char, Sector>,
+    // Line of the map directive, or zero if there wasn't one
+    map_line: usize,
+    rows: Vec<Row<'a>>,
+}
+
+fn parse_header<'a>(text: &'a str) -> Result<Header<'a>, LevelError> {
+    let mut section = Section::Header;
+
+    let mut header = Header {
+        grid_size: 3.0,
+        spawn: None,
+        props: Vec::new(),
+        legend: HashMap::new(),
+        map_line: 0,
+        rows: Vec::new(),
+    };
+
+    for (i, line) in text.lines().enumerate() {
+        let line_no = i + 1;
+        let fields = fields(line);
+
+        let (column, keyword) = match fields.first() {
+            Some(&(_, word)) if word.starts_with('#') => continue,
+            Some(&first) => first,
+            None => continue,
+        };
+
+        let err = |column, message: String| syntax(line_no, column, message);
+
+        match section {
+            Section::Map => {
+                header.rows.extend(Row::new(line_no, line));
+                continue;
+            },
+
+            _ if keyword == "map" => {
+                section = Section::Map;
+                header.map_line = line_no;
+                continue;
+            },
+
+            Section::Legend => {
+                if fields.len() != 4 {
+                    let message = "expected: glyph floor ceiling texture";
+                    return Err(err(column, message.into()));
+                }
+
+                let mut glyph = keyword.chars();
+                let glyph = match (glyph.next(), glyph.next()) {
+                    (Some(g), None) => g,
+                    _ => {
+                        let message = format!("glyph must be one character: {}", keyword);
+                        return Err(err(column, message));
+                    },
+                };
+
+                if header.legend.contains_key(&glyph) {
+                    return Err(err(column, format!("glyph defined twice: {}", glyph)));
+                }
+
+                let sector = Sector {
+                    floor_height: number(line_no, fields[1])?,
+                    ceiling_height: number(line_no, fields[2])?,
+                    texid: TextureID(number(line_no, fields[3])?),
+                };
+
+                header.legend.insert(glyph, sector);
+                continue;
+            },
+
+            Section::Header => (),
+        }
+
+        let args = &fields[1 ..];
+        let want_args = |n| {
+            if args.len() == n { return Ok(()); }
+            Err(err(column, format!("{} takes {} arguments", keyword, n)))
+        };
+
+        match keyword {
+            "grid" => {
+                want_args(1)?;
+                header.grid_size = number::<f32>(line_no, args[0])?;
+                if !(header.grid_size > 0.0) {
+                    return Err(err(args[0].0, "grid size must be positive".into()));
+                }
+            },
+
+            "spawn" => {
+                want_args(3)?;
+                let x = number(line_no, args[0])?;
+                let y = number(line_no, args[1])?;
+                let deg = number(line_no, args[2])?;
+                header.spawn = Some(Pos3D::new(x, y, 0.0, deg));
+            },
+
+            "prop" => {
+                want_args(2)?;
+                let x = number(line_no, args[0])?;
+                let y = number(line_no, args[1])?;
+                header.props.push(Vec2f::new(x, y));
+            },
+
+            "legend" => {
+                want_args(0)?;
+                section = Section::Legend;
+            },
+
+            _ => return Err(err(column, format!("unknown directive: {}", keyword))),
+        }
+    }
+
+    Ok(header)
+}
+
+impl<'a> Row<'a> {
+    // Skips blank lines and comments
+    fn new(line_no: usize, line: &'a str) -> Option<Self> {
+        let glyphs = line.trim();
+
+        if glyphs.is_empty() || glyphs.starts_with('#') {
+            return None;
+        }
+
+        let start = line.find(glyphs).unwrap();
+
+        Some(Row {
+            line: line_no,
+            column: line[.. start].chars().count() + 1,
+            glyphs: glyphs,
+        })
+    }
+
+    fn sectors(&self, legend: &HashMap<char, Sector>, width: usize)
+        -> Result<Vec<Sector>, LevelError>
+    {
+        let found = self.glyphs.chars().count();
+        if found != width {
+            let message = format!("expected {} columns, found {}", width, found);
+            return Err(syntax(self.line, self.column, message));
+        }
+
+        self.glyphs.chars().enumerate().map(|(offset, glyph)| {
+            legend.get(&glyph).cloned().ok_or_else(|| {
+                let message = format!("glyph not in legend: {}", glyph);
+                syntax(self.line, self.column + offset, message)
+            })
+        }).collect()
+    }
+}
+
+fn read<P: AsRef<Path>>(path: P) -> Result<String, LevelError> {
+    let mut text = String::new();
+    File::open(path)?.read_to_string(&mut text)?;
+    Ok(text)
+}
+
+// Splits a line on whitespace, keeping the 1-based column of each field,
+// counted in characters rather than bytes
+fn fields(line: &str) -> Vec<(usize, &str)> {
+    let mut fields = Vec::new();
+    let mut start = None;
+
+    for (i, c) in line.char_indices().chain(Some((line.len(), ' '))) {
+        match (start, c.is_whitespace()) {
+            (None, false) => start = Some(i),
+            (Some(s), true) => {
+                fields.push((line[.. s].chars().count() + 1, &line[s .. i]));
+                start = None;
+            },
+            _ => (),
+        }
+    }
+
+    fields
+}
+
+fn number<T: ::std::str::FromStr>(line: usize, (column, word): (usize, &str))
+    -> Result<T, LevelError>
+{
+    word.parse().map_err(|_| syntax(line, column, format!("bad number: {}", word)))
+}
+
+fn syntax(line: usize, column: usize, message: String) -> LevelError {
+    LevelError::Syntax {
+        line: line,
+        column: column,
+        message: message,
+    }
+}
+
+impl From<io::Error> for LevelError {
+    fn from(e: io::Error) -> Self {
+        LevelError::Io(e)
+    }
+}
+
+impl fmt::Display for LevelError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LevelError::Io(ref e) => write!(f, "{}", e),
+            LevelError::Syntax { line, column, ref message } => {
+                write!(f, "line {}, column {}: {}", line, column, message)
+            },
+
+            LevelError::NotALevel => write!(f, "not a level file"),
+
+            LevelError::Version(version) if version > VERSION => {
+                write!(f, "level file version {} is newer than this build \
+                    understands (up to {})", version, VERSION)
+            },
+
+            LevelError::Version(version) => {
+                write!(f, "unsupported level file version {}", version)
+            },
+
+            LevelError::Corrupt(ref what) => write!(f, "corrupt level file: {}", what),
+        }
+    }
+}
+
+impl Error for LevelError {}
+
+#[test]
+fn parse_debug_map() {
+    let level = LevelMap::new();
+    assert_eq!(level.grid_size, 3.0);
+    assert_eq!(level.spawn.0, Vec3f::new(13.0, 5.0, 0.0));
+
+    let pillar = level.sector(3, 4).unwrap();
+    assert_eq!(pillar.floor_height, 1);
+    assert_eq!(pillar.texid, TextureID(2));
+
+    let covered = level.sector(1, 6).unwrap();
+    assert_eq!(covered.ceiling_height, 1);
+
+    assert_eq!(level.props.len(), 3);
+    assert_eq!(level.props[0], Vec2f::new(7.5, 16.5));
+}
+
+#[test]
+fn parse_several_chunks() {
+    let level = LevelMap::parse(include_str!("../levels/courtyard.map")).unwrap();
+    assert_eq!(level.chunks().count(), 4);
+
+    // Sectors on either side of the boundary between chunks
+    assert_eq!(level.sector(7, 8).unwrap().floor_height, 1);
+    assert_eq!(level.sector(8, 8).unwrap().floor_height, 0);
+    assert_eq!(level.sector_at(Vec2f::new(23.9, 24.1)).unwrap().texid, TextureID(3));
+    assert!(level.sector(16, 0).is_none());
+    assert!(level.sector(-1, 0).is_none());
+}
+
+#[test]
+fn level_errors_have_positions() {
+    let error_at = |text: &str| match LevelMap::parse(text) {
+        Err(LevelError::Syntax { line, column, .. }) => (line, column),
+        other => panic!("expected a syntax error, got {:?}", other.map(|_| ())),
+    };
+
+    let map = "map\n..\n";
+    let legend = "legend\n. 0 0 0\nmap\n";
+    let rows = "........\n".repeat(7);
+
+    assert_eq!(error_at("grid 3\nflavor text\n"), (2, 1));
+    assert_eq!(error_at("grid three\n"), (1, 6));
+    assert_eq!(error_at("legend\n.. 0 0 0\n"), (2, 1));
+    assert_eq!(error_at("legend\n. 0 0 256\n"), (2, 7));
+    assert_eq!(error_at(map), (2, 1));
+    assert_eq!(error_at(&format!("{}{}  ..x.....\n", legend, rows)), (11, 5));
+    assert_eq!(error_at(&format!("{}{}.......\n", legend, rows)), (11, 1));
+    assert_eq!(error_at("grid 3\n"), (1, 1));
+
+    // Columns count characters, not bytes
+    assert_eq!(error_at("legend\né 0 0 0\nè 0 0 x\n"), (3, 7));
+    let legend = "legend\né 0 0 0\nmap\n";
+    let rows = "éééééééé\n".repeat(7);
+    assert_eq!(error_at(&format!("{}{}\u{a0}éé.ééééé\n", legend, rows)), (11, 4));
+}
+
+#[test]
+fn text_round_trip() {
+    let mut level = LevelMap::parse(include_str!("../levels/courtyard.map")).unwrap();
+    level.sector_mut(9, 3).unwrap().floor_height = -7;
+    level.props.push(Vec2f::new(1.5, 2.25));
+
+    let mut text = Vec::new();
+    level.write_text(&mut text).unwrap();
+    let copy = LevelMap::parse(&String::from_utf8(text).unwrap()).unwrap();
+
+    assert_eq!(copy.grid_size, level.grid_size);
+    assert_eq!(copy.spawn.0, level.spawn.0);
+    assert!((copy.spawn.1 - level.spawn.1).0.abs() < 1e-6);
+    assert_eq!(copy.props, level.props);
+    assert_eq!(copy.chunks().count(), level.chunks().count());
+
+    for chunk in level.chunks() {
+        let (coords, _) = LevelMap::locate(chunk.origin);
+        assert!(copy.chunk(coords).unwrap().sectors == chunk.sectors);
+    }
+
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("levels/sprawl");
+    let streamed = LevelMap::open_dir(dir).unwrap();
+    assert!(streamed.write_text(&mut Vec::new()).is_err());
+}
+
+#[test]
+fn stream_chunks_from_dir() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("levels/sprawl");
+    let mut level = LevelMap::open_dir(dir).unwrap();
+
+    let state = |level: &LevelMap, x, y| match level.lookup(x, y) {
+        Lookup::Loaded(_) => "loaded",
+        Lookup::Unloaded => "unloaded",
+        Lookup::Void => "void",
+    };
+
+    assert_eq!(level.chunks().count(), 0);
+    assert_eq!(state(&level, 4, 4), "unloaded");
+    assert_eq!(state(&level, -1, 4), "void");
+
+    // Chunk coordinates 0, 0 to 1, 1 are within one chunk of the spawn
+    level.stream_around(Vec2f::new(4.5, 24.0), 1, 2);
+    assert_eq!(level.chunks().count(), 4);
+    assert_eq!(state(&level, 15, 15), "loaded");
+    assert_eq!(state(&level, 16, 15), "unloaded");
+    assert_eq!(level.sector(2, 2).unwrap().texid, TextureID(2));
+
+    // Walking east drops the chunks left more than two behind
+    level.stream_around(Vec2f::new(100.0, 24.0), 1, 2);
+    assert_eq!(state(&level, 4, 4), "unloaded");
+    assert_eq!(state(&level, 12, 4), "unloaded");
+    assert_eq!(state(&level, 26, 4), "loaded");
+    assert_eq!(state(&level, 40, 4), "loaded");
+    assert_eq!(state(&level, 48, 4), "void");
+}
@@ -4,14 +4,120 @@ extern crate sdl2;
 extern crate specs;
 extern crate time;
 
+#[cfg(test)]
+extern crate png;
+
 pub mod geom;
+pub mod backend;
+pub mod binary;
+pub mod bindings;
+pub mod controllers;
+pub mod editor;
 pub mod engine;
+pub mod gen;
 pub mod input;
+pub mod level;
 pub mod movement;
 pub mod display;
 pub mod map;
+pub mod options;
+pub mod replay;
+pub mod texture;
+pub mod tick;
+
+#[cfg(test)]
+mod golden;
+
+fn load_level(path: &std::path::Path) -> Result<map::LevelMap, level::LevelError> {
+    if path.is_dir() {
+        map::LevelMap::open_dir(path)
+    } else {
+        map::LevelMap::load(path)
+    }
+}
+
+// Falls back to the defaults when there's no bindings file, unless one
+// was asked for by name
+fn load_bindings(options: &options::Options) -> Result<bindings::Bindings, String> {
+    let path = match options.bindings {
+        Some(ref path) => path.clone(),
+        None => std::path::PathBuf::from(bindings::BINDINGS_FILE),
+    };
+
+    if options.bindings.is_none() && !path.exists() {
+        return Ok(bindings::Bindings::default());
+    }
+
+    bindings::Bindings::load(&path).map_err(|e| format!("{}: {}", path.display(), e))
+}
 
 fn main() {
-    let mut engine = engine::new();
+    use std::env;
+    use std::process;
+
+    let options = match options::Options::parse(env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            println!("{}", e);
+            process::exit(2);
+        },
+    };
+
+    if options.help {
+        println!("{}", options::USAGE);
+        return;
+    }
+
+    let level = match (options.generate, options.map.as_ref()) {
+        (Some(style), _) => {
+            let seed = options.seed.unwrap_or_else(time::precise_time_ns);
+            println!("Generating {:?} level from seed {}", style, seed);
+            gen::generate(style, seed, geom::Vec2i::new(4, 4))
+        },
+
+        (None, Some(path)) => match load_level(path) {
+            Ok(level) => level,
+            Err(e) => {
+                println!("{}: {}", path.display(), e);
+                process::exit(1);
+            },
+        },
+
+        (None, None) => map::LevelMap::new(),
+    };
+
+    let bindings = match load_bindings(&options) {
+        Ok(bindings) => bindings,
+        Err(e) => {
+            println!("{}", e);
+            process::exit(1);
+        },
+    };
+
+    // Opened before the window, so that mistakes show up straight away
+    let recorder = options.record.as_ref().map(|path| {
+        replay::Recorder::create(path, &level).unwrap_or_else(|e| {
+            println!("{}: {}", path.display(), e);
+            process::exit(1);
+        })
+    });
+
+    let replay = options.replay.as_ref().map(|path| {
+        replay::Replay::open(path, &level).unwrap_or_else(|e| {
+            println!("{}: {}", path.display(), e);
+            process::exit(1);
+        })
+    });
+
+    let mut engine = engine::new(&options, level, bindings);
+
+    if let Some(recorder) = recorder {
+        engine.record(recorder);
+    }
+
+    if let Some(replay) = replay {
+        engine.replay(replay);
+    }
+
     engine.run();
 }
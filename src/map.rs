@@ -1,101 +1,116 @@
+use std::collections::HashMap;
+use std::collections::hash_map::Values;
+
 use specs::*;
 
+use engine::Ctx;
+
 use geom::*;
 
 use display::TextureID;
 
+use level::ChunkSource;
+
+use movement::IsPlayer;
+
 #[derive(Clone, Debug)]
 pub struct Pos3D(pub Vec3f, pub Radf);
 
+// Units per second
 #[derive(Clone, Debug)]
 pub struct Vel3D(pub Vec3f);
 
+// How an entity speeds up and slows down along the ground. Speeds are in
+// units per second and rates in units per second per second.
+#[derive(Clone, Debug)]
+pub struct Locomotion {
+    // Horizontal velocity the entity is trying to reach, set by whatever
+    // is steering it
+    pub wish: Vec2f,
+    pub max_speed: f32,
+    // How quickly it gets up to the wished-for velocity on the ground
+    pub acceleration: f32,
+    // How quickly it stops on the ground when not trying to go anywhere
+    pub friction: f32,
+    // Fraction of the acceleration it has in the air, which has no friction
+    pub air_control: f32,
+}
+
+// Up and down look angle; positive is up
+#[derive(Clone, Debug)]
+pub struct Pitch(pub Radf);
+
 impl Component for Pos3D { type Storage = VecStorage<Pos3D>; }
 
 impl Component for Vel3D { type Storage = VecStorage<Vel3D>; }
 
-static DEBUG_MAP: &'static str = {
-    r#"XXXXXXXX
-       X......X
-       X......X
-       X..a...X
-       X....b.X
-       X.c....X
-       X......X
-       XXXXXXXX"#
-};
+impl Component for Locomotion { type Storage = VecStorage<Locomotion>; }
+
+impl Component for Pitch { type Storage = VecStorage<Pitch>; }
+
+static DEBUG_MAP: &'static str = include_str!("../levels/debug.map");
+
+// Number of sectors along each side of a chunk
+pub const CHUNK_SIZE: i32 = 8;
 
 #[derive(Clone, Debug)]
 pub struct LevelMap {
-    pub chunks: Vec<Chunk>,
+    // Keyed by chunk coordinates, which are origins divided by CHUNK_SIZE
+    chunks: HashMap<Vec2i, Chunk>,
     pub grid_size: f32,
+    pub spawn: Pos3D,
+    // Where scenery stands, on whatever floor is there
+    pub props: Vec<Vec2f>,
+    // Set for levels too big to load all at once
+    pub source: Option<ChunkSource>,
+}
+
+// What's known about the sector at some coordinates
+#[derive(Copy, Clone, Debug)]
+pub enum Lookup {
+    Loaded(Sector),
+    // Exists, but its chunk hasn't been streamed in yet
+    Unloaded,
+    // Outside the level altogether
+    Void,
+}
+
+// Keeps the chunks around the player loaded in streamed levels
+pub struct StreamChunks {
+    // Distances in chunks, along whichever axis is further
+    pub load_radius: i32,
+    pub keep_radius: i32,
 }
 
 impl LevelMap {
     pub fn new() -> Self {
-        let mut sectors = [[Sector::default(); 8]; 8];
-        let mut x = 0;
-        let mut y = 7;
-        for c in DEBUG_MAP.chars() {
-            sectors[y][x] = match c {
-                '.' => Sector {
-                    floor_height: 0,
-                    texid: TextureID(0),
-                },
-
-                'X' => Sector {
-                    floor_height: 1,
-                    texid: TextureID(1),
-                },
-
-                'a' => Sector {
-                    floor_height: 1,
-                    texid: TextureID(2),
-                },
-
-                'b' => Sector {
-                    floor_height: 1,
-                    texid: TextureID(3),
-                },
-
-                'c' => Sector {
-                    floor_height: 1,
-                    texid: TextureID(4),
-                },
-
-                '\n' => {
-                    x = 0;
-                    y -= 1;
-                    continue;
-                },
-
-                _ => continue,
-            };
-
-            x += 1;
-        }
+        LevelMap::parse(DEBUG_MAP).unwrap()
+    }
 
+    pub fn empty(grid_size: f32, spawn: Pos3D) -> Self {
         LevelMap {
-            chunks: vec![Chunk { sectors: sectors }],
-            grid_size: 3.0,
+            chunks: HashMap::new(),
+            grid_size: grid_size,
+            spawn: spawn,
+            props: Vec::new(),
+            source: None,
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Chunk {
-    pub sectors: [[Sector; 8]; 8]
+    // Sector coordinates of the south-west corner. Always a multiple of
+    // CHUNK_SIZE; multiply by the grid size for world space.
+    pub origin: Vec2i,
+    pub sectors: [[Sector; CHUNK_SIZE as usize]; CHUNK_SIZE as usize]
 }
 
-impl Clone for Chunk {
-    fn clone(&self) -> Self {
-        unimplemented!()
-    }
-}
-
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub struct Sector {
     pub floor_height: i16,
+    // Zero leaves the sector open to the sky
+    pub ceiling_height: i16,
     pub texid: TextureID,
 }
 
@@ -103,17 +118,170 @@ impl Default for Sector {
     fn default() -> Self {
         Sector {
             floor_height: 0,
+            ceiling_height: 0,
             texid: TextureID::default(),
         }
     }
 }
 
+impl Chunk {
+    pub fn new(origin: Vec2i) -> Self {
+        Chunk {
+            origin: origin,
+            sectors: [[Sector::default(); CHUNK_SIZE as usize];
+                      CHUNK_SIZE as usize],
+        }
+    }
+}
+
+impl LevelMap {
+    // Replaces whatever chunk was at the same origin
+    pub fn insert_chunk(&mut self, chunk: Chunk) {
+        let (coords, local) = LevelMap::locate(chunk.origin);
+        assert_eq!(local, Vec2i::new(0, 0), "chunk origin not on the chunk grid");
+        self.chunks.insert(coords, chunk);
+    }
+
+    pub fn chunk(&self, coords: Vec2i) -> Option<&Chunk> {
+        self.chunks.get(&coords)
+    }
+
+    pub fn chunk_mut(&mut self, coords: Vec2i) -> Option<&mut Chunk> {
+        self.chunks.get_mut(&coords)
+    }
+
+    pub fn chunks<'a>(&'a self) -> Values<'a, Vec2i, Chunk> {
+        self.chunks.values()
+    }
+
+    // Splits sector coordinates into the coordinates of the chunk they
+    // fall in and the sector's offset within that chunk
+    pub fn locate(sector: Vec2i) -> (Vec2i, Vec2i) {
+        let chunk = Vec2i::new(sector.x.div_euclid(CHUNK_SIZE),
+                               sector.y.div_euclid(CHUNK_SIZE));
+        let local = Vec2i::new(sector.x.rem_euclid(CHUNK_SIZE),
+                               sector.y.rem_euclid(CHUNK_SIZE));
+        (chunk, local)
+    }
+
+    // Coordinates of the sector containing a point in world space
+    pub fn sector_coords(&self, pos: Vec2f) -> Vec2i {
+        let x = (pos.x / self.grid_size).floor() as i32;
+        let y = (pos.y / self.grid_size).floor() as i32;
+        Vec2i::new(x, y)
+    }
+
+    pub fn lookup(&self, x: i32, y: i32) -> Lookup {
+        let (chunk, local) = LevelMap::locate(Vec2i::new(x, y));
+
+        if let Some(chunk) = self.chunks.get(&chunk) {
+            return Lookup::Loaded(chunk.sectors[local.y as usize][local.x as usize]);
+        }
+
+        match self.source {
+            Some(ref source) if source.has_chunk(chunk) => Lookup::Unloaded,
+            _ => Lookup::Void,
+        }
+    }
+
+    pub fn lookup_at(&self, pos: Vec2f) -> Lookup {
+        let coords = self.sector_coords(pos);
+        self.lookup(coords.x, coords.y)
+    }
+
+    // Looks up a sector by grid coordinates, if it's loaded
+    pub fn sector(&self, x: i32, y: i32) -> Option<Sector> {
+        match self.lookup(x, y) {
+            Lookup::Loaded(sector) => Some(sector),
+            _ => None,
+        }
+    }
+
+    pub fn sector_mut(&mut self, x: i32, y: i32) -> Option<&mut Sector> {
+        let (chunk, local) = LevelMap::locate(Vec2i::new(x, y));
+        self.chunks.get_mut(&chunk).map(|chunk| {
+            &mut chunk.sectors[local.y as usize][local.x as usize]
+        })
+    }
+
+    pub fn sector_at(&self, pos: Vec2f) -> Option<Sector> {
+        let coords = self.sector_coords(pos);
+        self.sector(coords.x, coords.y)
+    }
+
+    // Loads the chunks within `load` chunks of `pos` and drops any further
+    // away than `keep`. Levels that were loaded whole are left alone.
+    pub fn stream_around(&mut self, pos: Vec2f, load: i32, keep: i32) {
+        let (center, _) = LevelMap::locate(self.sector_coords(pos));
+
+        let source = match self.source {
+            Some(ref mut source) => source,
+            None => return,
+        };
+
+        self.chunks.retain(|coords, _| {
+            let offset = coords - center;
+            offset.x.abs().max(offset.y.abs()) <= keep
+        });
+
+        for y in center.y - load .. center.y + load + 1 {
+            for x in center.x - load .. center.x + load + 1 {
+                let coords = Vec2i::new(x, y);
+
+                if self.chunks.contains_key(&coords) { continue; }
+                if !source.has_chunk(coords) { continue; }
+
+                match source.load_chunk(coords) {
+                    Ok(chunk) => {
+                        self.chunks.insert(coords, chunk);
+                    },
+
+                    Err(e) => {
+                        println!("{}: {}", source.chunk_path(coords).display(), e);
+                        source.forget(coords);
+                    },
+                }
+            }
+        }
+    }
+}
+
+impl System<Ctx> for StreamChunks {
+    fn run(&mut self, arg: RunArg, _ctx: Ctx) {
+        let (mut level, pos, player) = arg.fetch(|world| {
+            (world.write_resource::<LevelMap>(),
+            world.read::<Pos3D>(),
+            world.read::<IsPlayer>())
+        });
+
+        for (_, pos) in (&player, &pos).iter() {
+            let pos = pos.0.truncate();
+            level.stream_around(pos, self.load_radius, self.keep_radius);
+        }
+    }
+}
+
 impl Pos3D {
     pub fn new(x: f32, y: f32, z: f32, deg: f32) -> Self {
         let pos = Vec3f::new(x, y, z);
         let yaw = Rad(deg.to_radians());
         Pos3D(pos, yaw)
     }
+
+    // Partway from here to `to`, turning the short way round
+    pub fn lerp(&self, to: &Pos3D, alpha: f32) -> Pos3D {
+        use cgmath::Angle;
+        use std::f32::consts::PI;
+
+        let pos = self.0 + (to.0 - self.0) * alpha;
+
+        let mut turn = (to.1 - self.1).normalize();
+        if turn.0 > PI {
+            turn.0 -= 2.0 * PI;
+        }
+
+        Pos3D(pos, (self.1 + turn * alpha).normalize())
+    }
 }
 
 impl Vel3D {
@@ -121,3 +289,95 @@ impl Vel3D {
         Vel3D(Vec3f::new(0.0, 0.0, 0.0))
     }
 }
+
+impl Locomotion {
+    // Horizontal velocity after `dt` seconds of moving `vel` towards the
+    // wished-for velocity, without overshooting it
+    pub fn steer(&self, vel: Vec2f, on_ground: bool, dt: f32) -> Vec2f {
+        use cgmath::prelude::*;
+
+        let mut wish = self.wish;
+        if wish.magnitude() > self.max_speed {
+            wish = wish.normalize_to(self.max_speed);
+        }
+
+        let idle = wish == Vec2f::new(0.0, 0.0);
+        let rate = match (on_ground, idle) {
+            (true, false) => self.acceleration,
+            (true, true) => self.friction,
+            (false, false) => self.acceleration * self.air_control,
+            (false, true) => 0.0,
+        };
+
+        let change = wish - vel;
+        let step = rate * dt;
+
+        if change.magnitude() <= step {
+            wish
+        } else {
+            vel + change.normalize_to(step)
+        }
+    }
+}
+
+impl Pitch {
+    pub fn new() -> Self {
+        Pitch(Rad(0.0))
+    }
+}
+
+#[test]
+fn lerp_turns_short_way() {
+    let a = Pos3D::new(0.0, 0.0, 0.0, 350.0);
+    let b = Pos3D::new(2.0, 4.0, 1.0, 10.0);
+
+    let mid = a.lerp(&b, 0.5);
+    assert_eq!(mid.0, Vec3f::new(1.0, 2.0, 0.5));
+    assert!((mid.1).0.abs() < 1e-5 || ((mid.1).0 - 2.0 * ::std::f32::consts::PI).abs() < 1e-5);
+
+    let quarter = b.lerp(&a, 0.25);
+    assert!(((quarter.1).0.to_degrees() - 5.0).abs() < 1e-3);
+}
+
+#[test]
+fn locate_negative_sectors() {
+    let locate = |x, y| {
+        let (chunk, local) = LevelMap::locate(Vec2i::new(x, y));
+        ((chunk.x, chunk.y), (local.x, local.y))
+    };
+
+    assert_eq!(locate(0, 0), ((0, 0), (0, 0)));
+    assert_eq!(locate(9, 7), ((1, 0), (1, 7)));
+    assert_eq!(locate(-1, -8), ((-1, -1), (7, 0)));
+    assert_eq!(locate(-9, 16), ((-2, 2), (7, 0)));
+}
+
+#[test]
+fn steer_speeds_up_and_stops() {
+    use cgmath::prelude::*;
+
+    let mut loco = Locomotion {
+        wish: Vec2f::new(10.0, 0.0),
+        max_speed: 6.0,
+        acceleration: 40.0,
+        friction: 30.0,
+        air_control: 0.25,
+    };
+    let dt = 0.05;
+
+    // Gets up to speed over a few ticks, no faster than the max
+    let vel = loco.steer(Vec2f::new(0.0, 0.0), true, dt);
+    assert_eq!(vel, Vec2f::new(2.0, 0.0));
+    let vel = (0 .. 10).fold(vel, |vel, _| loco.steer(vel, true, dt));
+    assert_eq!(vel, Vec2f::new(6.0, 0.0));
+
+    // Turning in the air is slower than on the ground
+    loco.wish = Vec2f::new(0.0, 6.0);
+    let turned = loco.steer(vel, false, dt) - vel;
+    assert!((turned.magnitude() - 0.5).abs() < 1e-5, "{:?}", turned);
+
+    // Friction stops it on the ground, but it keeps going in the air
+    loco.wish = Vec2f::new(0.0, 0.0);
+    assert_eq!(loco.steer(vel, true, dt), Vec2f::new(4.5, 0.0));
+    assert_eq!(loco.steer(vel, false, dt), vel);
+}
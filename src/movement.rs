@@ -1,7 +1,11 @@
-use specs::{Join, RunArg, System};
+use std::collections::HashMap;
+
+use specs::{Join, RunArg, System, VecStorage};
 
 use engine::*;
 
+use geom::*;
+
 use map::*;
 
 use specs::{Component, NullStorage};
@@ -10,24 +14,508 @@ use specs::{Component, NullStorage};
 pub struct Collider {
     pub height: f32,
     pub radius: f32,
+    // Tallest rise in the floor that can be walked up without jumping
+    pub step_height: f32,
+}
+
+// Something that falls, and stands on the floor when it isn't falling.
+// Needs a Collider too.
+#[derive(Clone, Debug, Default)]
+pub struct Body {
+    pub on_ground: bool,
 }
 
 #[derive(Copy, Clone, Debug, Default)]
 pub struct IsPlayer;
 
+// Scenery to bump into, drawn as a sprite when there's a display
+#[derive(Copy, Clone, Debug, Default)]
+pub struct IsProp;
+
+// Units per second per second
+pub const GRAVITY: f32 = 24.0;
+
+impl Component for Collider { type Storage = VecStorage<Collider>; }
+
+impl Component for Body { type Storage = VecStorage<Body>; }
+
 impl Component for IsPlayer { type Storage = NullStorage<IsPlayer>; }
 
+impl Component for IsProp { type Storage = NullStorage<IsProp>; }
+
 pub struct ApplyVelocity;
 
+// Pushes apart entities whose colliders overlap. Anything without a
+// velocity stays put, so whatever walks into it takes the whole push.
+pub struct Separate;
+
+// Which entities are near each sector, to only test for collisions between
+// entities that could be touching
+pub struct SpatialHash {
+    grid_size: f32,
+    cells: HashMap<Vec2i, Vec<usize>>,
+}
+
 impl System<Ctx> for ApplyVelocity {
+    fn run(&mut self, arg: RunArg, ctx: Ctx) {
+        let (level, collider, loco, mut body, mut pos, mut vel, entities) = arg.fetch(|world| {
+            (world.read_resource::<LevelMap>(),
+            world.read::<Collider>(),
+            world.read::<Locomotion>(),
+            world.write::<Body>(),
+            world.write::<Pos3D>(),
+            world.write::<Vel3D>(),
+            world.entities())
+        });
+
+        let dt = ctx.dt as f32;
+
+        for (entity, pos, vel) in (&entities, &mut pos, &mut vel).iter() {
+            // Anything that can't fall is always on the ground
+            if let Some(loco) = loco.get(entity) {
+                let on_ground = body.get(entity).is_none_or(|body| body.on_ground);
+                let vel_xy = loco.steer(vel.0.truncate(), on_ground, dt);
+                vel.0.x = vel_xy.x;
+                vel.0.y = vel_xy.y;
+            }
+
+            match (collider.get(entity), body.get_mut(entity)) {
+                (Some(collider), Some(body)) => {
+                    collider.fall(&level, body, &mut pos.0, &mut vel.0, dt);
+                },
+
+                (Some(collider), None) => collider.walk(&level, &mut pos.0, vel.0 * dt),
+                _ => pos.0 += vel.0 * dt,
+            }
+        }
+    }
+}
+
+impl System<Ctx> for Separate {
     fn run(&mut self, arg: RunArg, _ctx: Ctx) {
-        let (mut pos, mut vel) = arg.fetch(|world| {
-            (world.write::<Pos3D>(), world.write::<Vel3D>())
+        let (level, collider, vel, mut pos, entities) = arg.fetch(|world| {
+            (world.read_resource::<LevelMap>(),
+            world.read::<Collider>(),
+            world.read::<Vel3D>(),
+            world.write::<Pos3D>(),
+            world.entities())
         });
 
-        for (pos, vel) in (&mut pos, &mut vel).iter() {
-            pos.0 += vel.0;
-            //vel.0 = Vec3f::new();
+        let bodies = (&entities, &pos, &collider).iter()
+            .map(|(entity, pos, collider)| (entity, pos.0, collider, vel.get(entity).is_some()))
+            .collect::<Vec<_>>();
+
+        let mut hash = SpatialHash::new(level.grid_size);
+        for (i, &(_, pos, collider, _)) in bodies.iter().enumerate() {
+            hash.insert(i, pos.truncate(), collider.radius);
+        }
+
+        let mut pushes = vec![Vec2f::new(0.0, 0.0); bodies.len()];
+
+        for (i, &(_, pos, collider, moves)) in bodies.iter().enumerate() {
+            for j in hash.near(pos.truncate(), collider.radius) {
+                if j <= i { continue; }
+
+                let (_, other_pos, other, other_moves) = bodies[j];
+                let push = match collider.overlap(pos, other, other_pos) {
+                    Some(push) => push,
+                    None => continue,
+                };
+
+                // How much of the push the other one takes
+                let share = match (moves, other_moves) {
+                    (true, true) => 0.5,
+                    (true, false) => 0.0,
+                    (false, true) => 1.0,
+                    (false, false) => continue,
+                };
+
+                pushes[i] -= push * (1.0 - share);
+                pushes[j] += push * share;
+            }
+        }
+
+        for (&(entity, _, collider, _), push) in bodies.iter().zip(pushes) {
+            if push == Vec2f::new(0.0, 0.0) { continue; }
+
+            // Through slide, so nothing gets pushed into a wall
+            let pos = pos.get_mut(entity).unwrap();
+            collider.slide(&level, &mut pos.0, push.extend(0.0));
         }
     }
 }
+
+impl SpatialHash {
+    pub fn new(grid_size: f32) -> Self {
+        SpatialHash { grid_size: grid_size, cells: HashMap::new() }
+    }
+
+    // Adds `index` to every sector the circle touches
+    pub fn insert(&mut self, index: usize, pos: Vec2f, radius: f32) {
+        for cell in self.cells_under(pos, radius) {
+            self.cells.entry(cell).or_default().push(index);
+        }
+    }
+
+    // Everything inserted into a sector the circle touches, in order and
+    // without repeats
+    pub fn near(&self, pos: Vec2f, radius: f32) -> Vec<usize> {
+        let mut near = self.cells_under(pos, radius).iter()
+            .filter_map(|cell| self.cells.get(cell))
+            .flat_map(|indices| indices.iter().cloned())
+            .collect::<Vec<_>>();
+
+        near.sort();
+        near.dedup();
+        near
+    }
+
+    fn cells_under(&self, pos: Vec2f, radius: f32) -> Vec<Vec2i> {
+        let cell = |x: f32| (x / self.grid_size).floor() as i32;
+
+        let (x0, x1) = (cell(pos.x - radius), cell(pos.x + radius));
+        let (y0, y1) = (cell(pos.y - radius), cell(pos.y + radius));
+
+        let mut cells = Vec::new();
+        for y in y0 .. y1 + 1 {
+            for x in x0 .. x1 + 1 {
+                cells.push(Vec2i::new(x, y));
+            }
+        }
+        cells
+    }
+}
+
+impl Collider {
+    // Moves a body through one tick of `dt` seconds: sideways with `slide`,
+    // then up or down under gravity. Walking off a ledge no taller than the
+    // step height keeps the body on the ground; anything taller is a fall.
+    pub fn fall(&self, level: &LevelMap, body: &mut Body, pos: &mut Vec3f,
+                vel: &mut Vec3f, dt: f32) {
+        if !body.on_ground {
+            vel.z -= GRAVITY * dt;
+        }
+
+        self.slide(level, pos, Vec3f::new(vel.x, vel.y, 0.0) * dt);
+        pos.z += vel.z * dt;
+
+        let (floor, ceiling) = self.bounds(level, *pos);
+
+        if let Some(ceiling) = ceiling {
+            if pos.z + self.height > ceiling {
+                pos.z = ceiling - self.height;
+                vel.z = vel.z.min(0.0);
+            }
+        }
+
+        body.on_ground = match floor {
+            Some(floor) if pos.z <= floor => true,
+            Some(floor) => body.on_ground && vel.z <= 0.0 && pos.z - floor <= self.step_height,
+            None => false,
+        };
+
+        if body.on_ground {
+            pos.z = floor.unwrap();
+            vel.z = 0.0;
+        }
+    }
+
+    // Moves something that doesn't fall, like `slide` but then standing it
+    // on the highest floor underfoot, however far down that is
+    pub fn walk(&self, level: &LevelMap, pos: &mut Vec3f, delta: Vec3f) {
+        self.slide(level, pos, delta);
+
+        if let (Some(floor), _) = self.bounds(level, *pos) {
+            pos.z = floor;
+        }
+    }
+
+    // Moves the feet at `pos` by `delta`, sliding along anything in the
+    // way and stepping up onto any floor low enough to climb. Never moves
+    // down; that's up to `fall` or `walk`.
+    pub fn slide(&self, level: &LevelMap, pos: &mut Vec3f, delta: Vec3f) {
+        let start = *pos;
+        let feet = start.z;
+
+        pos.x += delta.x;
+        pos.y += delta.y;
+
+        // Being pushed out of one sector can push us into its neighbor
+        for _ in 0 .. 2 {
+            self.push_out(level, pos, feet);
+        }
+
+        let grid = level.grid_size;
+        let cell = |x: f32| (x / grid).floor() as i32;
+
+        // Too fast to push out cleanly, so don't move at all
+        if self.blocked(level, cell(pos.x), cell(pos.y), feet) {
+            *pos = start;
+            return;
+        }
+
+        if let (Some(floor), _) = self.bounds(level, *pos) {
+            pos.z = floor.max(feet);
+        }
+    }
+
+    // The highest floor and lowest ceiling over the sectors the circle
+    // around `pos` overlaps, leaving out any too high to step onto
+    fn bounds(&self, level: &LevelMap, pos: Vec3f) -> (Option<f32>, Option<f32>) {
+        let grid = level.grid_size;
+        let cell = |x: f32| (x / grid).floor() as i32;
+
+        let (x0, x1) = (cell(pos.x - self.radius), cell(pos.x + self.radius));
+        let (y0, y1) = (cell(pos.y - self.radius), cell(pos.y + self.radius));
+
+        let mut floor = None;
+        let mut ceiling = None;
+
+        for y in y0 .. y1 + 1 {
+            for x in x0 .. x1 + 1 {
+                let sector = match level.sector(x, y) {
+                    Some(sector) => sector,
+                    None => continue,
+                };
+
+                let z = sector.floor_height as f32 * grid;
+                if z - pos.z > self.step_height { continue; }
+
+                floor = Some(floor.map_or(z, |f: f32| f.max(z)));
+
+                if sector.ceiling_height != 0 {
+                    let z = sector.ceiling_height as f32 * grid;
+                    ceiling = Some(ceiling.map_or(z, |c: f32| c.min(z)));
+                }
+            }
+        }
+
+        (floor, ceiling)
+    }
+
+    fn push_out(&self, level: &LevelMap, pos: &mut Vec3f, feet: f32) {
+        let grid = level.grid_size;
+        let cell = |x: f32| (x / grid).floor() as i32;
+
+        let (x0, x1) = (cell(pos.x - self.radius), cell(pos.x + self.radius));
+        let (y0, y1) = (cell(pos.y - self.radius), cell(pos.y + self.radius));
+
+        for y in y0 .. y1 + 1 {
+            for x in x0 .. x1 + 1 {
+                if !self.blocked(level, x, y, feet) { continue; }
+
+                // Nearest point of the sector to the center of the circle
+                let min = Vec2f::new(x as f32, y as f32) * grid;
+                let near_x = pos.x.clamp(min.x, min.x + grid);
+                let near_y = pos.y.clamp(min.y, min.y + grid);
+
+                let away = Vec2f::new(pos.x - near_x, pos.y - near_y);
+                let dist = (away.x * away.x + away.y * away.y).sqrt();
+
+                if dist > 0.0 && dist < self.radius {
+                    let push = away * ((self.radius - dist) / dist);
+                    pos.x += push.x;
+                    pos.y += push.y;
+                } else if dist == 0.0 {
+                    // The center is inside, so leave by the nearest side
+                    let max = min + Vec2f::new(grid, grid);
+                    let exits = [
+                        (pos.x - min.x, Vec2f::new(min.x - self.radius, pos.y)),
+                        (max.x - pos.x, Vec2f::new(max.x + self.radius, pos.y)),
+                        (pos.y - min.y, Vec2f::new(pos.x, min.y - self.radius)),
+                        (max.y - pos.y, Vec2f::new(pos.x, max.y + self.radius)),
+                    ];
+
+                    let mut exit = exits[0];
+                    for &e in exits.iter() {
+                        if e.0 < exit.0 { exit = e; }
+                    }
+
+                    pos.x = (exit.1).x;
+                    pos.y = (exit.1).y;
+                }
+            }
+        }
+    }
+
+    // Highest rise in the floor it can get onto, by jumping at `jump_speed`
+    // and stepping up at the top of the jump
+    pub fn reach(&self, jump_speed: f32) -> f32 {
+        self.step_height + jump_speed * jump_speed / (2.0 * GRAVITY)
+    }
+
+    // How far the collider at `other_pos` has to move to stop overlapping
+    // this one at `pos`, if they overlap
+    pub fn overlap(&self, pos: Vec3f, other: &Collider, other_pos: Vec3f) -> Option<Vec2f> {
+        use cgmath::prelude::*;
+
+        if pos.z >= other_pos.z + other.height || other_pos.z >= pos.z + self.height {
+            return None;
+        }
+
+        let away = Vec2f::new(other_pos.x - pos.x, other_pos.y - pos.y);
+        let dist = away.magnitude();
+        let min = self.radius + other.radius;
+
+        if dist >= min {
+            None
+        } else if dist > 0.0 {
+            Some(away * ((min - dist) / dist))
+        } else {
+            // Right on top of each other, so pick a way
+            Some(Vec2f::new(min, 0.0))
+        }
+    }
+
+    // Whether a sector is too high to step up onto, or too low-ceilinged
+    // to fit under, for feet at height `feet`
+    fn blocked(&self, level: &LevelMap, x: i32, y: i32, feet: f32) -> bool {
+        let sector = match level.sector(x, y) {
+            Some(sector) => sector,
+            None => return true,
+        };
+
+        let grid = level.grid_size;
+        let floor = sector.floor_height as f32 * grid;
+
+        if floor - feet > self.step_height {
+            return true;
+        }
+
+        if sector.ceiling_height != 0 {
+            let ceiling = sector.ceiling_height as f32 * grid;
+            if ceiling - floor.max(feet) < self.height {
+                return true;
+            }
+        }
+
+        false
+    }
+}
+
+#[test]
+fn slide_along_wall() {
+    let level = LevelMap::new();
+    let collider = Collider { height: 1.8, radius: 0.4, step_height: 1.0 };
+
+    // Walking diagonally into the west wall slides north along it
+    let mut pos = Vec3f::new(3.5, 10.5, 0.0);
+    collider.slide(&level, &mut pos, Vec3f::new(-0.5, 0.5, 0.0));
+    assert!((pos.x - 3.4).abs() < 0.001, "{:?}", pos);
+    assert!((pos.y - 11.0).abs() < 0.001, "{:?}", pos);
+
+    // The pillars are a whole sector tall, far above the step height
+    let mut pos = Vec3f::new(5.5, 7.5, 0.0);
+    collider.slide(&level, &mut pos, Vec3f::new(0.3, 0.0, 0.0));
+    assert!((pos.x - 5.6).abs() < 0.001, "{:?}", pos);
+    assert_eq!(pos.z, 0.0);
+
+    // A tall enough step can be climbed
+    let climber = Collider { step_height: 3.0, .. collider };
+    let mut pos = Vec3f::new(5.5, 7.5, 0.0);
+    climber.slide(&level, &mut pos, Vec3f::new(0.3, 0.0, 0.0));
+    assert!((pos.x - 5.8).abs() < 0.001, "{:?}", pos);
+    assert_eq!(pos.z, 3.0);
+
+    // Sliding never goes back down, but walking does
+    let mut slid = pos;
+    climber.slide(&level, &mut slid, Vec3f::new(-0.6, 0.0, 0.0));
+    assert_eq!(slid.z, 3.0);
+    climber.walk(&level, &mut pos, Vec3f::new(-0.6, 0.0, 0.0));
+    assert_eq!(pos.z, 0.0);
+}
+
+#[test]
+fn fall_land_and_jump() {
+    let level = LevelMap::new();
+    let collider = Collider { height: 1.8, radius: 0.4, step_height: 1.0 };
+
+    // Runs `ticks` ticks, returning the highest the feet got
+    let run = |pos: &mut Vec3f, vel: &mut Vec3f, body: &mut Body, ticks| {
+        let mut top = pos.z;
+        for _ in 0 .. ticks {
+            collider.fall(&level, body, pos, vel, 1.0 / 60.0);
+            top = top.max(pos.z);
+        }
+        top
+    };
+
+    // Walking off the top of a pillar is a fall, not a step
+    let mut pos = Vec3f::new(7.5, 7.5, 3.0);
+    let mut vel = Vec3f::new(-3.0, 0.0, 0.0);
+    let mut body = Body { on_ground: true };
+    run(&mut pos, &mut vel, &mut body, 45);
+    assert!(!body.on_ground && pos.z > 0.0 && pos.z < 3.0, "{:?}", pos);
+
+    run(&mut pos, &mut vel, &mut body, 60);
+    assert!(body.on_ground, "{:?}", pos);
+    assert_eq!((pos.z, vel.z), (0.0, 0.0));
+
+    // A running jump gets back up onto it
+    let mut pos = Vec3f::new(4.5, 7.5, 0.0);
+    let mut vel = Vec3f::new(3.0, 0.0, 11.0);
+    run(&mut pos, &mut vel, &mut body, 60);
+    assert!(body.on_ground && pos.x > 6.0, "{:?}", pos);
+    assert_eq!(pos.z, 3.0);
+
+    // Jumping under a low ceiling bumps the head on it
+    let mut pos = Vec3f::new(5.5, 16.5, 0.0);
+    let mut vel = Vec3f::new(0.0, 0.0, 11.0);
+    let top = run(&mut pos, &mut vel, &mut body, 60);
+    assert!((top - 1.2).abs() < 0.001, "{}", top);
+    assert!(body.on_ground && pos.z == 0.0);
+}
+
+#[test]
+fn entities_push_apart() {
+    use cgmath::prelude::*;
+    use engine;
+    use input::MoveIntent;
+
+    // Walks the player north for a second into something at (13, 7), which
+    // moves if it has a velocity, and returns where both ended up
+    let bump = |moves: bool| {
+        let mut planner = engine::simulation(LevelMap::new());
+
+        let other = {
+            let world = planner.mut_world();
+            let mut other = world.create_now()
+                .with(Pos3D::new(13.0, 7.0, 0.0, 0.0))
+                .with(Collider { height: 2.0, radius: 0.5, step_height: 0.0 });
+            if moves {
+                other = other.with(Vel3D::new());
+            }
+            other.build()
+        };
+
+        let mut ctx = Ctx::new();
+        ctx.intent = MoveIntent { forward: 1.0, .. MoveIntent::default() };
+        for _ in 0 .. 60 {
+            planner.dispatch(ctx.for_tick());
+        }
+
+        let world = planner.mut_world();
+        let player = world.read::<IsPlayer>();
+        let pos = world.read::<Pos3D>();
+        let player_pos = (&player, &pos).iter().next().unwrap().1;
+        (player_pos.0, pos.get(other).unwrap().0)
+    };
+
+    let apart = |a: Vec3f, b: Vec3f| (a - b).truncate().magnitude();
+
+    // Something fixed in place stops the player
+    let (player, prop) = bump(false);
+    assert_eq!(prop, Vec3f::new(13.0, 7.0, 0.0));
+    assert!(apart(player, prop) > 0.9 - 1e-4, "{:?}", player);
+
+    // Anything else gets shoved along
+    let (player, npc) = bump(true);
+    assert!(npc.y > 7.5, "{:?}", npc);
+    assert!(apart(player, npc) > 0.9 - 1e-4, "{:?} {:?}", player, npc);
+
+    // Colliders only touch if they overlap in height too
+    let collider = Collider { height: 1.8, radius: 0.4, step_height: 1.0 };
+    let below = Vec3f::new(0.0, 0.0, 0.0);
+    assert!(collider.overlap(below, &collider, Vec3f::new(0.5, 0.0, 0.0)).is_some());
+    assert!(collider.overlap(below, &collider, Vec3f::new(0.5, 0.0, 2.0)).is_none());
+}
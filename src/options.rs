@@ -0,0 +1,165 @@
+use std::path::PathBuf;
+
+use display::Resolution;
+
+use gen::Style;
+
+use geom::*;
+
+pub const USAGE: &'static str = "\
+usage: rayhem [options] [map]
+
+    map                 Level file to play (default: the built-in debug map)
+
+    --windowed          Run in a window instead of fullscreen
+    --fullscreen        Run fullscreen at the desktop resolution (default)
+    --window WxH        Size of the window when windowed (default 640x480)
+    --resolution WxH    Render at exactly this size
+    --budget PIXELS     Largest internal resolution area to pick automatically
+    --generate STYLE    Play a new level: rooms, caves or terraces
+    --seed N            Seed for --generate (default: random)
+    --bindings FILE     Controls to use (default: assets/bindings.cfg)
+    --record FILE       Save every tick's input to a file
+    --replay FILE       Play back a recording made on the same level";
+
+#[derive(Clone, Debug)]
+pub struct Options {
+    pub fullscreen: bool,
+    pub window_size: Vec2u,
+    pub resolution: Resolution,
+    pub map: Option<PathBuf>,
+    pub generate: Option<Style>,
+    pub seed: Option<u64>,
+    pub bindings: Option<PathBuf>,
+    pub record: Option<PathBuf>,
+    pub replay: Option<PathBuf>,
+    // Just print the usage and quit
+    pub help: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            fullscreen: true,
+            window_size: Vec2u::new(640, 480),
+            resolution: Resolution::default(),
+            map: None,
+            generate: None,
+            seed: None,
+            bindings: None,
+            record: None,
+            replay: None,
+            help: false,
+        }
+    }
+}
+
+impl Options {
+    // Parses command line arguments, not including the program name
+    pub fn parse<I>(args: I) -> Result<Self, String>
+        where I: IntoIterator<Item=String>
+    {
+        let mut options = Options::default();
+        let mut args = args.into_iter();
+
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next().ok_or(format!("{} needs a value", arg))
+            };
+
+            match arg.as_str() {
+                "--windowed" => options.fullscreen = false,
+                "--fullscreen" => options.fullscreen = true,
+                "--window" => options.window_size = parse_size(&value()?)?,
+
+                "--resolution" => {
+                    options.resolution = Resolution::Fixed(parse_size(&value()?)?);
+                },
+
+                "--budget" => {
+                    let value = value()?;
+                    let area = value.parse::<u32>().ok()
+                        .filter(|&area| area > 0)
+                        .ok_or(format!("bad pixel budget: {}", value))?;
+                    options.resolution = Resolution::Budget(area);
+                },
+
+                "--generate" => options.generate = Some(value()?.parse()?),
+
+                "--seed" => {
+                    let value = value()?;
+                    let seed = value.parse::<u64>()
+                        .map_err(|_| format!("bad seed: {}", value))?;
+                    options.seed = Some(seed);
+                },
+
+                "--bindings" => options.bindings = Some(PathBuf::from(value()?)),
+                "--record" => options.record = Some(PathBuf::from(value()?)),
+                "--replay" => options.replay = Some(PathBuf::from(value()?)),
+
+                // Whatever else is there doesn't matter
+                "-h" | "--help" => {
+                    options.help = true;
+                    return Ok(options);
+                },
+
+                _ if !arg.starts_with('-') && options.map.is_none() => {
+                    options.map = Some(PathBuf::from(arg));
+                },
+
+                _ => return Err(format!("unexpected argument: {}\n\n{}", arg, USAGE)),
+            }
+        }
+
+        Ok(options)
+    }
+}
+
+fn parse_size(s: &str) -> Result<Vec2u, String> {
+    let mut parts = s.splitn(2, 'x').map(|n| n.parse::<u32>().ok());
+
+    match (parts.next(), parts.next()) {
+        (Some(Some(w)), Some(Some(h))) if w > 0 && h > 0 => Ok(Vec2u::new(w, h)),
+        _ => Err(format!("bad size (expected WxH): {}", s)),
+    }
+}
+
+#[test]
+fn parse_options() {
+    let args = |list: &[&str]| {
+        Options::parse(list.iter().map(|s| s.to_string()))
+    };
+
+    let options = args(&["--windowed", "--window", "800x600",
+                         "--resolution", "200x150"]).unwrap();
+    assert!(!options.fullscreen);
+    assert_eq!(options.window_size, Vec2u::new(800, 600));
+    assert_eq!(options.resolution, Resolution::Fixed(Vec2u::new(200, 150)));
+
+    let options = args(&["--budget", "50000"]).unwrap();
+    assert!(options.fullscreen);
+    assert_eq!(options.resolution, Resolution::Budget(50000));
+    assert_eq!(options.map, None);
+
+    let options = args(&["levels/debug.map", "--windowed"]).unwrap();
+    assert_eq!(options.map, Some(PathBuf::from("levels/debug.map")));
+    assert!(args(&["one.map", "two.map"]).is_err());
+
+    let options = args(&["--generate", "caves", "--seed", "7"]).unwrap();
+    assert_eq!(options.generate, Some(Style::Caves));
+    assert_eq!(options.seed, Some(7));
+    assert!(args(&["--generate", "mazes"]).is_err());
+
+    let options = args(&["--bindings", "azerty.cfg"]).unwrap();
+    assert_eq!(options.bindings, Some(PathBuf::from("azerty.cfg")));
+
+    let options = args(&["--replay", "bug.rhrp", "--record", "copy.rhrp"]).unwrap();
+    assert_eq!(options.replay, Some(PathBuf::from("bug.rhrp")));
+    assert_eq!(options.record, Some(PathBuf::from("copy.rhrp")));
+
+    assert!(args(&["--window", "800"]).is_err());
+    assert!(args(&["--resolution"]).is_err());
+    assert!(args(&["--budget", "0"]).is_err());
+    assert!(args(&["--frobnicate"]).is_err());
+    assert!(args(&["--help", "--frobnicate"]).unwrap().help);
+}
@@ -0,0 +1,296 @@
+// Recordings of what the player did on each tick. Ticks are a fixed length
+// and the simulation only sees its inputs, so playing a recording back
+// against the same level retraces the same path exactly. Edits to the
+// level aren't recorded, so the editor stays off while recording or
+// replaying. Everything is little-endian:
+//
+//     magic        4 bytes, "RHRP"
+//     version      u16
+//     tick rate    f64
+//     level        u64, the fingerprint of the level as it started
+//     ticks        to the end of the file, each as f32 turn amount,
+//                  f32 look amount, f32 forward, f32 strafe, f32 turn,
+//                  f32 look, then a u8 of flags: 1 to run, 2 to quit,
+//                  4 to jump
+
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use engine::Ctx;
+
+use map::LevelMap;
+
+use input::MoveIntent;
+
+use tick::TICK_RATE;
+
+pub const MAGIC: &'static [u8; 4] = b"RHRP";
+
+// Bump this whenever a tick plays out differently, as well as when the
+// layout changes, since old recordings would silently go somewhere else
+pub const VERSION: u16 = 4;
+
+const RUN: u8 = 1;
+const QUIT: u8 = 2;
+const JUMP: u8 = 4;
+
+// The parts of a Ctx that the simulation reads
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TickInput {
+    pub turn_amount: f32,
+    pub look_amount: f32,
+    pub intent: MoveIntent,
+    pub should_quit: bool,
+}
+
+pub struct Recorder<W: Write> {
+    w: W,
+}
+
+pub struct Replay<R: Read> {
+    r: R,
+}
+
+impl TickInput {
+    pub fn from_ctx(ctx: &Ctx) -> Self {
+        TickInput {
+            turn_amount: ctx.turn_amount,
+            look_amount: ctx.look_amount,
+            intent: ctx.intent,
+            should_quit: ctx.should_quit,
+        }
+    }
+
+    pub fn apply(&self, ctx: &mut Ctx) {
+        ctx.turn_amount = self.turn_amount;
+        ctx.look_amount = self.look_amount;
+        ctx.intent = self.intent;
+        ctx.should_quit = self.should_quit;
+    }
+
+    fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let intent = &self.intent;
+        let values = [self.turn_amount, self.look_amount,
+                      intent.forward, intent.strafe, intent.turn, intent.look];
+
+        for &n in values.iter() {
+            w.write_all(&n.to_le_bytes())?;
+        }
+
+        let flags = if intent.run { RUN } else { 0 }
+                  | if self.should_quit { QUIT } else { 0 }
+                  | if intent.jump { JUMP } else { 0 };
+        w.write_all(&[flags])
+    }
+
+    fn read_from<R: Read>(r: &mut R) -> io::Result<Self> {
+        let mut values = [0.0; 6];
+        for n in values.iter_mut() {
+            let mut buf = [0; 4];
+            r.read_exact(&mut buf)?;
+            *n = f32::from_le_bytes(buf);
+        }
+
+        let mut flags = [0];
+        r.read_exact(&mut flags)?;
+
+        Ok(TickInput {
+            turn_amount: values[0],
+            look_amount: values[1],
+            intent: MoveIntent {
+                forward: values[2],
+                strafe: values[3],
+                turn: values[4],
+                look: values[5],
+                run: flags[0] & RUN != 0,
+                jump: flags[0] & JUMP != 0,
+            },
+            should_quit: flags[0] & QUIT != 0,
+        })
+    }
+}
+
+impl Recorder<BufWriter<File>> {
+    pub fn create<P: AsRef<Path>>(path: P, level: &LevelMap) -> io::Result<Self> {
+        Recorder::new(BufWriter::new(File::create(path)?), level)
+    }
+}
+
+impl<W: Write> Recorder<W> {
+    pub fn new(mut w: W, level: &LevelMap) -> io::Result<Self> {
+        w.write_all(MAGIC)?;
+        w.write_all(&VERSION.to_le_bytes())?;
+        w.write_all(&TICK_RATE.to_le_bytes())?;
+        w.write_all(&level.fingerprint()?.to_le_bytes())?;
+        Ok(Recorder { w: w })
+    }
+
+    pub fn record(&mut self, input: &TickInput) -> io::Result<()> {
+        input.write_to(&mut self.w)
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        self.w.flush()?;
+        Ok(self.w)
+    }
+}
+
+impl Replay<BufReader<File>> {
+    pub fn open<P: AsRef<Path>>(path: P, level: &LevelMap) -> io::Result<Self> {
+        Replay::new(BufReader::new(File::open(path)?), level)
+    }
+}
+
+impl<R: Read> Replay<R> {
+    // Checks that the recording was made on `level`
+    pub fn new(mut r: R, level: &LevelMap) -> io::Result<Self> {
+        let bad = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+
+        let mut magic = [0; 4];
+        r.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(bad("not a recording".into()));
+        }
+
+        let mut version = [0; 2];
+        r.read_exact(&mut version)?;
+        let version = u16::from_le_bytes(version);
+        if version != VERSION {
+            return Err(bad(format!("unsupported recording version {}", version)));
+        }
+
+        // A different tick rate would play the same inputs out differently
+        let mut rate = [0; 8];
+        r.read_exact(&mut rate)?;
+        let rate = f64::from_le_bytes(rate);
+        if rate != TICK_RATE {
+            return Err(bad(format!("recorded at {} ticks per second, not {}", rate, TICK_RATE)));
+        }
+
+        // So would a different level, say one generated from another seed
+        let mut fingerprint = [0; 8];
+        r.read_exact(&mut fingerprint)?;
+        if u64::from_le_bytes(fingerprint) != level.fingerprint()? {
+            return Err(bad("recorded on a different level".into()));
+        }
+
+        Ok(Replay { r: r })
+    }
+}
+
+impl<R: Read> Iterator for Replay<R> {
+    type Item = io::Result<TickInput>;
+
+    // Stops cleanly at the end of the file, but not partway through a tick
+    fn next(&mut self) -> Option<io::Result<TickInput>> {
+        let mut first = [0; 1];
+
+        match self.r.read(&mut first) {
+            Ok(0) => return None,
+            Ok(_) => (),
+            Err(e) => return Some(Err(e)),
+        }
+
+        let mut rest = (&first[..]).chain(&mut self.r);
+        Some(TickInput::read_from(&mut rest))
+    }
+}
+
+#[test]
+fn replay_retraces_path() {
+    use std::collections::BTreeMap;
+
+    use engine;
+    use geom::Vec3f;
+    use map::*;
+    use movement::IsPlayer;
+    use specs::Join;
+    use tick::Ticks;
+
+    // Plays through the same loop as the game, frame by frame, noting
+    // where the player is after however many ticks each frame brings
+    fn play<F, I>(ticks: &mut Ticks<Vec<u8>, &[u8]>, frames: u32, fps: F,
+                  input: I) -> (BTreeMap<u32, (Vec3f, f32)>, Ctx)
+        where F: Fn(u32) -> f64, I: Fn(u32, &mut Ctx)
+    {
+        let mut planner = engine::simulation(LevelMap::new());
+        let mut ctx = Ctx::new();
+        let mut path = BTreeMap::new();
+        let mut ticked = 0;
+
+        for frame in 0 .. frames {
+            ctx.dt = 1.0 / fps(frame);
+            input(frame, &mut ctx);
+
+            ticked += ticks.run(&mut planner, &mut ctx);
+            if ctx.should_quit { break; }
+
+            // Drawing in between ticks mustn't move anything
+            planner.dispatch(ctx.clone());
+
+            let world = planner.mut_world();
+            let (player, pos) = (world.read::<IsPlayer>(), world.read::<Pos3D>());
+            let pos = (&player, &pos).iter().next().unwrap().1;
+            path.insert(ticked, (pos.0, (pos.1).0));
+        }
+
+        (path, ctx)
+    }
+
+    // Record at an uneven 20 to 40 frames per second, turning the mouse
+    // now and then, weaving, running and jumping
+    let level = LevelMap::new();
+    let mut ticks = Ticks::new();
+    ticks.recorder = Some(Recorder::new(Vec::new(), &level).unwrap());
+
+    let uneven = |frame| if frame % 3 == 0 { 20.0 } else { 40.0 };
+    let (recorded, ctx) = play(&mut ticks, 120, uneven, |frame, ctx| {
+        let phase = frame as f32 / 10.0;
+        ctx.turn_amount += if frame % 7 == 0 { 3.0 } else { 0.0 };
+        ctx.look_amount += phase.cos();
+        ctx.intent = MoveIntent {
+            forward: phase.sin(),
+            strafe: (phase * 0.3).cos(),
+            turn: 0.2,
+            look: 0.0,
+            run: frame % 20 < 10,
+            jump: frame % 16 == 0,
+        };
+    });
+
+    let bytes = ticks.finish(&ctx).unwrap().unwrap();
+
+    // Play it back at 144 frames per second until the recording quits,
+    // with the player trying to walk the other way
+    let mut ticks = Ticks::new();
+    ticks.replay = Some(Replay::new(&bytes[..], &level).unwrap());
+
+    let (replayed, ctx) = play(&mut ticks, 10000, |_| 144.0, |_, ctx| {
+        ctx.intent = MoveIntent { forward: -1.0, .. MoveIntent::default() };
+    });
+
+    assert!(ctx.should_quit);
+
+    // Every tick count comes up at 144 frames per second, so each spot the
+    // recording passed through can be checked
+    assert!(recorded.len() > 50);
+    assert_eq!(replayed.keys().last(), recorded.keys().last());
+    assert!(recorded.values().last().unwrap().0 != level.spawn.0);
+
+    for (tick, spot) in &recorded {
+        assert_eq!(replayed.get(tick), Some(spot));
+    }
+
+    let truncated = Replay::new(&bytes[.. bytes.len() - 3], &level).unwrap()
+        .collect::<io::Result<Vec<_>>>();
+    assert_eq!(truncated.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+
+    let mut other_rate = bytes.clone();
+    other_rate[6 .. 14].copy_from_slice(&30.0f64.to_le_bytes());
+    assert!(Replay::new(&other_rate[..], &level).is_err());
+    assert!(Replay::new(&b"RHLV"[..], &level).is_err());
+
+    let courtyard = LevelMap::parse(include_str!("../levels/courtyard.map")).unwrap();
+    assert!(Replay::new(&bytes[..], &courtyard).is_err());
+}
@@ -0,0 +1,186 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use sdl2::pixels::{Color, PixelFormatEnum};
+use sdl2::surface::Surface;
+
+use backend::Framebuffer;
+
+use display::TextureID;
+
+use geom::*;
+
+/// RGBA texels, row-major, top row first. Lookups wrap in both directions.
+#[derive(Clone, Debug)]
+pub struct Texture {
+    pub size: Vec2u,
+    pub pixels: Vec<u8>,
+}
+
+pub struct Atlas {
+    textures: HashMap<TextureID, Texture>,
+}
+
+impl Texture {
+    pub fn solid(color: Color) -> Self {
+        let (r, g, b, a) = color.rgba();
+
+        Texture {
+            size: Vec2u::new(1, 1),
+            pixels: vec![r, g, b, a],
+        }
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        use sdl2::image::LoadSurface;
+        use sdl2::render::BlendMode;
+
+        let mut src = Surface::from_file(path)?;
+        let (width, height) = src.size();
+
+        // Blit into a surface of known layout; SDL does the conversion
+        let mut dst = Surface::new(width, height, PixelFormatEnum::ABGR8888)?;
+        src.set_blend_mode(BlendMode::None)?;
+        src.blit(None, &mut dst, None)?;
+
+        let row_len = width as usize * 4;
+        let pitch = dst.pitch() as usize;
+
+        let pixels = dst.with_lock(|data| {
+            let mut pixels = Vec::with_capacity(row_len * height as usize);
+            for row in data.chunks(pitch).take(height as usize) {
+                pixels.extend_from_slice(&row[.. row_len]);
+            }
+            pixels
+        });
+
+        Ok(Texture {
+            size: Vec2u::new(width, height),
+            pixels: pixels,
+        })
+    }
+
+    #[inline]
+    pub fn get(&self, x: u32, y: u32) -> Color {
+        let x = (x % self.size.x) as usize;
+        let y = (y % self.size.y) as usize;
+        let i = (y * self.size.x as usize + x) * 4;
+        let px = &self.pixels[i .. i + 4];
+        Color::RGBA(px[0], px[1], px[2], px[3])
+    }
+
+    // Samples at texture coordinates where 1.0 spans the whole texture
+    #[inline]
+    pub fn sample(&self, u: f32, v: f32) -> Color {
+        let x = ((u - u.floor()) * self.size.x as f32) as u32;
+        let y = ((v - v.floor()) * self.size.y as f32) as u32;
+        self.get(x, y)
+    }
+
+    // Draws the column of texels at `u` stretched over screen rows y0..y1,
+    // with `v0` and `v1` the texture coordinates at either end.
+    pub fn draw_column(&self, frame: &mut Framebuffer, x: i32,
+                       (y0, y1): (i32, i32), u: f32, (v0, v1): (f32, f32))
+    {
+        if y1 <= y0 { return; }
+
+        let height = frame.size.y as i32;
+        let dv = (v1 - v0) / (y1 - y0) as f32;
+
+        for y in y0.max(0) .. y1.min(height) {
+            let v = v0 + dv * ((y - y0) as f32 + 0.5);
+            frame.put(x, y, self.sample(u, v));
+        }
+    }
+}
+
+impl Atlas {
+    pub fn new() -> Self {
+        Atlas {
+            textures: HashMap::new(),
+        }
+    }
+
+    // Flat colors to fall back on when there are no texture files
+    pub fn placeholders() -> Self {
+        let mut atlas = Atlas::new();
+
+        let colors = [
+            Color::RGB(0x5f, 0x5f, 0x5f),
+            Color::RGB(0x7f, 0x3f, 0x1f),
+            Color::RGB(0x00, 0xbf, 0x1f),
+            Color::RGB(0xbf, 0xbf, 0x1f),
+            Color::RGB(0xbf, 0xbf, 0xbf),
+        ];
+
+        for (i, &color) in colors.iter().enumerate() {
+            atlas.insert(TextureID(i as u8), Texture::solid(color));
+        }
+
+        atlas
+    }
+
+    pub fn insert(&mut self, id: TextureID, texture: Texture) {
+        self.textures.insert(id, texture);
+    }
+
+    pub fn get(&self, id: TextureID) -> Option<&Texture> {
+        self.textures.get(&id)
+    }
+
+    // Every ID with a texture, in order
+    pub fn ids(&self) -> Vec<TextureID> {
+        let mut ids = self.textures.keys().cloned().collect::<Vec<_>>();
+        ids.sort_by_key(|id| id.0);
+        ids
+    }
+
+    // Loads every file named <id>.png in `dir`, replacing existing entries.
+    // Returns the number of textures loaded.
+    pub fn load_dir<P: AsRef<Path>>(&mut self, dir: P) -> Result<usize, String> {
+        let entries = fs::read_dir(dir).map_err(|e| e.to_string())?;
+        let mut count = 0;
+
+        for entry in entries {
+            let path = entry.map_err(|e| e.to_string())?.path();
+
+            if path.extension().and_then(|e| e.to_str()) != Some("png") {
+                continue;
+            }
+
+            let id = match path.file_stem().and_then(|s| s.to_str()) {
+                Some(stem) => match stem.parse::<u8>() {
+                    Ok(id) => TextureID(id),
+                    Err(_) => continue,
+                },
+
+                None => continue,
+            };
+
+            let texture = Texture::load(&path).map_err(|e| {
+                format!("{}: {}", path.display(), e)
+            })?;
+
+            self.insert(id, texture);
+            count += 1;
+        }
+
+        Ok(count)
+    }
+}
+
+#[test]
+fn sample_wraps() {
+    let texture = Texture {
+        size: Vec2u::new(2, 1),
+        pixels: vec![
+            0x00, 0x00, 0x00, 0xff,
+            0xff, 0xff, 0xff, 0xff,
+        ],
+    };
+
+    assert_eq!(texture.sample(0.25, 0.0).rgba(), (0x00, 0x00, 0x00, 0xff));
+    assert_eq!(texture.sample(0.75, 0.0).rgba(), (0xff, 0xff, 0xff, 0xff));
+    assert_eq!(texture.sample(-0.25, 3.5).rgba(), (0xff, 0xff, 0xff, 0xff));
+}
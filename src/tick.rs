@@ -0,0 +1,264 @@
+// The simulation runs in fixed ticks, however fast frames are drawn. Each
+// frame runs as many ticks as have come due and then draws everything
+// partway between the last two, so that movement looks smooth at any
+// frame rate but plays out the same at all of them.
+
+use std::io::{self, Read, Write};
+
+use specs::{Component, Join, Planner, RunArg, System, VecStorage};
+
+use engine::Ctx;
+
+use map::*;
+
+use replay::{Recorder, Replay, TickInput};
+
+// Ticks per second
+pub const TICK_RATE: f64 = 60.0;
+
+// Longest frame to catch up on. After a stall, say from dragging the window,
+// the game slows down instead of running seconds of ticks at once.
+const MAX_FRAME: f64 = 0.25;
+
+// Which half of the loop a dispatch is for
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Phase {
+    // Advancing the simulation by one tick
+    Tick,
+    // Drawing a frame
+    Frame,
+}
+
+// Runs the system it wraps only in one phase
+pub struct InPhase<S> {
+    phase: Phase,
+    system: S,
+}
+
+// Where an entity was before the latest tick, to draw it between there and
+// where it is now
+#[derive(Clone, Debug)]
+pub struct PrevPos3D(pub Pos3D);
+
+impl Component for PrevPos3D { type Storage = VecStorage<PrevPos3D>; }
+
+// Keeps PrevPos3D up to date. Has to run before anything moves.
+pub struct RememberPositions;
+
+pub struct Timestep {
+    // Time that has passed but not been ticked through yet, in ticks
+    accumulator: f64,
+}
+
+// The half of the loop that advances the simulation, which needs nothing
+// to draw with
+pub struct Ticks<W: Write, R: Read> {
+    timestep: Timestep,
+    pub recorder: Option<Recorder<W>>,
+    // Takes over from the player's input until it runs out
+    pub replay: Option<Replay<R>>,
+}
+
+impl<S> InPhase<S> {
+    pub fn tick(system: S) -> Self {
+        InPhase { phase: Phase::Tick, system: system }
+    }
+
+    pub fn frame(system: S) -> Self {
+        InPhase { phase: Phase::Frame, system: system }
+    }
+}
+
+impl<S: System<Ctx>> System<Ctx> for InPhase<S> {
+    fn run(&mut self, arg: RunArg, ctx: Ctx) {
+        if ctx.phase == self.phase {
+            self.system.run(arg, ctx);
+        } else {
+            // The planner waits for every system to fetch something
+            arg.fetch(|_| ());
+        }
+    }
+}
+
+impl System<Ctx> for RememberPositions {
+    fn run(&mut self, arg: RunArg, _ctx: Ctx) {
+        let (pos, mut prev, entities) = arg.fetch(|world| {
+            (world.read::<Pos3D>(),
+            world.write::<PrevPos3D>(),
+            world.entities())
+        });
+
+        for (entity, pos) in (&entities, &pos).iter() {
+            prev.insert(entity, PrevPos3D(pos.clone()));
+        }
+    }
+}
+
+impl Timestep {
+    pub fn new() -> Self {
+        Timestep { accumulator: 0.0 }
+    }
+
+    // Length of a tick in seconds
+    pub fn dt() -> f64 {
+        1.0 / TICK_RATE
+    }
+
+    // Takes in a frame that lasted `frame_dt` seconds and returns how many
+    // ticks are due
+    pub fn advance(&mut self, frame_dt: f64) -> u32 {
+        self.accumulator += frame_dt.clamp(0.0, MAX_FRAME) * TICK_RATE;
+
+        // Frames of exactly one tick shouldn't lose one to rounding
+        let ticks = (self.accumulator + 1e-6).floor();
+        self.accumulator = (self.accumulator - ticks).max(0.0);
+        ticks as u32
+    }
+
+    // How far into the next tick we are, from 0 to 1
+    pub fn alpha(&self) -> f32 {
+        self.accumulator.min(1.0) as f32
+    }
+}
+
+impl<W: Write, R: Read> Ticks<W, R> {
+    pub fn new() -> Self {
+        Ticks {
+            timestep: Timestep::new(),
+            recorder: None,
+            replay: None,
+        }
+    }
+
+    // Runs the ticks that a frame lasting `ctx.dt` brings due, then sets
+    // `ctx.alpha` for drawing it. Returns how many ran, stopping early and
+    // setting `ctx.should_quit` if one of them quits.
+    pub fn run(&mut self, planner: &mut Planner<Ctx>, ctx: &mut Ctx) -> u32 {
+        let mut ran = 0;
+
+        for _ in 0 .. self.timestep.advance(ctx.dt) {
+            let tick = self.next_tick(ctx);
+
+            if tick.should_quit {
+                ctx.should_quit = true;
+                break;
+            }
+
+            planner.dispatch(tick);
+            ran += 1;
+
+            // Mouse motion only counts towards the first tick after it
+            ctx.turn_amount = 0.0;
+            ctx.look_amount = 0.0;
+        }
+
+        ctx.alpha = self.timestep.alpha();
+        ran
+    }
+
+    // Ends the recording, if there is one, with a tick that quits
+    pub fn finish(&mut self, ctx: &Ctx) -> io::Result<Option<W>> {
+        let mut recorder = match self.recorder.take() {
+            Some(recorder) => recorder,
+            None => return Ok(None),
+        };
+
+        let mut quit = ctx.for_tick();
+        quit.should_quit = true;
+
+        recorder.record(&TickInput::from_ctx(&quit))?;
+        recorder.finish().map(Some)
+    }
+
+    // The input for the next tick, from the replay if there is one, and
+    // recorded if we're recording
+    fn next_tick(&mut self, ctx: &Ctx) -> Ctx {
+        let mut tick = ctx.for_tick();
+
+        if let Some(next) = self.replay.as_mut().map(|replay| replay.next()) {
+            match next {
+                Some(Ok(input)) => input.apply(&mut tick),
+
+                Some(Err(e)) => {
+                    println!("Stopping replay: {}", e);
+                    self.replay = None;
+                },
+
+                None => {
+                    println!("Replay finished");
+                    self.replay = None;
+                },
+            }
+        }
+
+        let recorded = match self.recorder {
+            Some(ref mut recorder) => recorder.record(&TickInput::from_ctx(&tick)),
+            None => Ok(()),
+        };
+
+        if let Err(e) = recorded {
+            println!("Stopping recording: {}", e);
+            self.recorder = None;
+        }
+
+        tick
+    }
+}
+
+#[test]
+fn ticks_ignore_frame_rate() {
+    let count = |frame_dt: f64, frames: u32| {
+        let mut timestep = Timestep::new();
+        let ticks = (0 .. frames).map(|_| timestep.advance(frame_dt)).sum::<u32>();
+        (ticks, timestep.alpha())
+    };
+
+    // Two seconds at 30, 60, 144 and 1000 frames per second
+    assert_eq!(count(1.0 / 30.0, 60).0, 120);
+    assert_eq!(count(1.0 / 60.0, 120).0, 120);
+    assert_eq!(count(1.0 / 144.0, 288).0, 120);
+    assert_eq!(count(1.0 / 1000.0, 2000).0, 120);
+
+    let (ticks, alpha) = count(1.0 / 240.0, 2);
+    assert_eq!(ticks, 0);
+    assert!((alpha - 0.5).abs() < 1e-4);
+
+    // A long stall only catches up on a quarter of a second
+    assert_eq!(count(5.0, 1).0, 15);
+}
+
+#[test]
+fn same_path_at_any_frame_rate() {
+    use engine;
+    use input::MoveIntent;
+    use movement::IsPlayer;
+
+    let walk = |fps: f64| {
+        let mut planner = engine::simulation(LevelMap::new());
+
+        let mut ctx = Ctx::new();
+        ctx.intent = MoveIntent { forward: 1.0, strafe: 0.5, turn: 0.3, .. MoveIntent::default() };
+
+        let mut timestep = Timestep::new();
+
+        // Two seconds of walking in a curve, into the walls and along them
+        for _ in 0 .. (2.0 * fps) as u32 {
+            for _ in 0 .. timestep.advance(1.0 / fps) {
+                planner.dispatch(ctx.for_tick());
+            }
+
+            // Drawing in between doesn't move anything
+            planner.dispatch(ctx.clone());
+        }
+
+        let world = planner.mut_world();
+        let (player, pos) = (world.read::<IsPlayer>(), world.read::<Pos3D>());
+        let pos = (&player, &pos).iter().next().unwrap().1;
+        (pos.0, (pos.1).0)
+    };
+
+    let slow = walk(30.0);
+    assert!(slow.0 != LevelMap::new().spawn.0);
+    assert_eq!(walk(60.0), slow);
+    assert_eq!(walk(144.0), slow);
+}